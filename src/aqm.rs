@@ -0,0 +1,169 @@
+// aqm.rs - Active queue management for the crypto worker queue.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! CoDel active queue management as described in RFC 8289.
+//!
+//! Drop decisions are driven by the sojourn time each packet spent
+//! in the reader to crypto worker queue. The state is shared by all
+//! crypto workers draining the queue.
+
+use std::sync::{Arc, Mutex};
+
+
+/// Default acceptable standing queue delay in milliseconds.
+pub const DEFAULT_TARGET: u64 = 5;
+
+/// Default sliding window in milliseconds over which
+/// the minimum sojourn time is tracked.
+pub const DEFAULT_INTERVAL: u64 = 100;
+
+/// A snapshot of the AQM state and counters.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct AqmStats {
+    pub dequeued: u64,
+    pub dropped: u64,
+    pub dropping: bool,
+    pub drop_count: u32,
+}
+
+#[derive(Default)]
+struct CodelState {
+    first_above_time: u64,
+    drop_next: u64,
+    count: u32,
+    last_count: u32,
+    dropping: bool,
+    dequeued: u64,
+    dropped: u64,
+}
+
+/// Codel is a cheaply cloneable handle to the shared CoDel state.
+#[derive(Clone)]
+pub struct Codel {
+    target: u64,
+    interval: u64,
+    state: Arc<Mutex<CodelState>>,
+}
+
+impl Codel {
+    pub fn new(target: u64, interval: u64) -> Codel {
+        Codel {
+            target: target,
+            interval: interval,
+            state: Arc::new(Mutex::new(CodelState::default())),
+        }
+    }
+
+    fn control_law(&self, t: u64, count: u32) -> u64 {
+        t + (self.interval as f64 / (count as f64).sqrt()) as u64
+    }
+
+    /// Decide whether the packet just taken off the queue should
+    /// be dropped. `sojourn` is the time in milliseconds the packet
+    /// spent queued, `now` is the current time in milliseconds and
+    /// `queue_len` is the number of packets still waiting.
+    pub fn should_drop(&self, sojourn: u64, now: u64, queue_len: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.dequeued += 1;
+
+        let mut ok_to_drop = false;
+        if sojourn < self.target || queue_len == 0 {
+            state.first_above_time = 0;
+        } else if state.first_above_time == 0 {
+            state.first_above_time = now + self.interval;
+        } else if now >= state.first_above_time {
+            ok_to_drop = true;
+        }
+
+        let mut drop = false;
+        if state.dropping {
+            if !ok_to_drop {
+                state.dropping = false;
+            } else if now >= state.drop_next {
+                drop = true;
+                state.count += 1;
+                let drop_next = state.drop_next;
+                state.drop_next = self.control_law(drop_next, state.count);
+            }
+        } else if ok_to_drop {
+            drop = true;
+            state.dropping = true;
+            let delta = state.count.saturating_sub(state.last_count);
+            state.count = if delta > 1 && now.saturating_sub(state.drop_next) < 16 * self.interval {
+                delta
+            } else {
+                1
+            };
+            state.drop_next = self.control_law(now, state.count);
+            state.last_count = state.count;
+        }
+        if drop {
+            state.dropped += 1;
+        }
+        drop
+    }
+
+    pub fn stats(&self) -> AqmStats {
+        let state = self.state.lock().unwrap();
+        AqmStats {
+            dequeued: state.dequeued,
+            dropped: state.dropped,
+            dropping: state.dropping,
+            drop_count: state.count,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codel_below_target_test() {
+        let codel = Codel::new(DEFAULT_TARGET, DEFAULT_INTERVAL);
+        for now in 0..1000 {
+            assert!(!codel.should_drop(DEFAULT_TARGET - 1, now, 10));
+        }
+        let stats = codel.stats();
+        assert_eq!(stats.dequeued, 1000);
+        assert_eq!(stats.dropped, 0);
+        assert!(!stats.dropping);
+    }
+
+    #[test]
+    fn codel_standing_queue_test() {
+        let codel = Codel::new(DEFAULT_TARGET, DEFAULT_INTERVAL);
+
+        // Above target but not yet for a full interval.
+        assert!(!codel.should_drop(50, 1000, 10));
+        assert!(!codel.should_drop(50, 1050, 10));
+
+        // A full interval above target enters the dropping state.
+        assert!(codel.should_drop(50, 1100, 10));
+        assert!(codel.stats().dropping);
+
+        // The next drop is scheduled interval / sqrt(count) later.
+        assert!(!codel.should_drop(50, 1150, 10));
+        assert!(codel.should_drop(50, 1200, 10));
+        assert_eq!(codel.stats().drop_count, 2);
+
+        // Falling below target leaves the dropping state.
+        assert!(!codel.should_drop(1, 1210, 10));
+        assert!(!codel.stats().dropping);
+        assert_eq!(codel.stats().dropped, 2);
+    }
+}
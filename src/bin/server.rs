@@ -17,9 +17,25 @@
 
 extern crate clap;
 extern crate mix_server;
+extern crate signal_hook;
+
+use std::process;
 
 use clap::{Arg, App};
-//use mix_server::server::Server;
+use mix_server::config::Config;
+use mix_server::errors::ServerError;
+use mix_server::server::Server;
+use signal_hook::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+/// Exit code for an unreadable or invalid configuration file.
+const EXIT_CONFIG: i32 = 2;
+
+/// Exit code for link, identity or mix key loading failures.
+const EXIT_KEYS: i32 = 3;
+
+/// Exit code for any other startup failure.
+const EXIT_STARTUP: i32 = 1;
 
 fn main() {
     let matches = App::new("mixnet server")
@@ -29,10 +45,65 @@ fn main() {
         .arg(Arg::with_name("config")
              .short("c")
              .long("config_file")
-             .required(true)
+             .required_unless("generate")
              .value_name("FILE")
              .help("Specifies the configuration file.")
              .takes_value(true))
+        .arg(Arg::with_name("generate")
+             .long("generate-config")
+             .value_name("FILE")
+             .help("Writes a commented example configuration file and exits.")
+             .takes_value(true))
         .get_matches();
-    let _config_file_path = matches.value_of("config").unwrap();
+
+    if let Some(path) = matches.value_of("generate") {
+        if let Err(e) = Config::example().store(path.to_string()) {
+            eprintln!("failed to write configuration file {}: {}", path, e);
+            process::exit(EXIT_CONFIG);
+        }
+        return
+    }
+    let config_file_path = matches.value_of("config").unwrap();
+    let cfg = match Config::load_file(config_file_path.to_string()) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("failed to load configuration file {}: {}", config_file_path, e);
+            process::exit(EXIT_CONFIG);
+        },
+    };
+    if let Err(e) = cfg.validate() {
+        eprintln!("{}: {}", config_file_path, e);
+        process::exit(EXIT_CONFIG);
+    }
+
+    let signals = match Signals::new(&[SIGINT, SIGTERM]) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("failed to install signal handlers: {}", e);
+            process::exit(EXIT_STARTUP);
+        },
+    };
+    let mut server = match Server::new(cfg) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(EXIT_CONFIG);
+        },
+    };
+    if let Err(e) = server.run() {
+        eprintln!("mix server failed to start: {}", e);
+        let code = match e {
+            ServerError::LinkKeyError(_) |
+            ServerError::IdentityKeyError(_) |
+            ServerError::MixKeyError(_) => EXIT_KEYS,
+            _ => EXIT_STARTUP,
+        };
+        process::exit(code);
+    }
+
+    // Run until asked to stop, then shut down gracefully.
+    if let Some(signal) = signals.forever().next() {
+        eprintln!("received signal {}, shutting down", signal);
+    }
+    server.shutdown();
 }
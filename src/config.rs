@@ -14,18 +14,105 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+extern crate crossbeam_channel;
+
+use std::cmp;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::prelude::*;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use log::LevelFilter;
+use num_cpus;
+use rustc_serialize::base64::FromBase64;
 use toml;
 
+use super::aqm;
+use super::constants;
 use super::errors::ConfigError;
+use super::pki::{link_key_from_base64, identity_key_from_base64};
+use super::kaetzchen::ECHO_SERVICE;
+use super::decoy::LOOP_SERVICE;
+
+
+/// Default maximum time in milliseconds a packet may wait for a
+/// crypto worker before it is dropped. Congestion is managed by the
+/// AQM, this only bounds the wait so it must exceed the AQM target.
+pub const DEFAULT_SLACK_TIME: u64 = 1000;
+
+/// Default line rate in packets per second, used to size
+/// the replay caches.
+pub const DEFAULT_LINE_RATE: u64 = 128_000;
+
+pub const DEFAULT_LOG_LEVEL: &str = "INFO";
+
+/// Default number of rotated log files kept.
+pub const DEFAULT_MAX_LOG_FILES: u32 = 5;
+
+/// Default number of requests a plugin may be handling at once.
+pub const DEFAULT_PLUGIN_CONCURRENCY: usize = 4;
+
+/// Base64 encoding of an all zero key, the example's stand in
+/// for the authority keys.
+const PLACEHOLDER_KEY: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
 
+fn default_num_workers() -> u16 {
+    cmp::min(cmp::max(num_cpus::get(), 1), u16::max_value() as usize) as u16
+}
+
+fn default_slack_time() -> u64 {
+    DEFAULT_SLACK_TIME
+}
+
+fn default_line_rate() -> u64 {
+    DEFAULT_LINE_RATE
+}
+
+fn default_log_level() -> String {
+    DEFAULT_LOG_LEVEL.to_string()
+}
 
+fn default_max_log_files() -> u32 {
+    DEFAULT_MAX_LOG_FILES
+}
+
+fn default_plugin_concurrency() -> usize {
+    DEFAULT_PLUGIN_CONCURRENCY
+}
+
+/// Logging goes to stderr unless `log_file` is set. Files are rotated
+/// once they reach `max_file_size` bytes, zero disables rotation.
+/// `modules` overrides the global level for individual modules,
+/// e.g. `"mix_server::wire_worker" = "debug"`.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Logging {
+    #[serde(default)]
     pub disable: bool,
+    #[serde(default)]
     pub log_file: String,
+    #[serde(default = "default_log_level")]
     pub level: String,
+    #[serde(default)]
+    pub max_file_size: u64,
+    #[serde(default = "default_max_log_files")]
+    pub max_files: u32,
+    #[serde(default)]
+    pub modules: BTreeMap<String, String>,
+}
+
+impl Default for Logging {
+    fn default() -> Logging {
+        Logging {
+            disable: false,
+            log_file: String::new(),
+            level: default_log_level(),
+            max_file_size: 0,
+            max_files: DEFAULT_MAX_LOG_FILES,
+            modules: BTreeMap::new(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -33,11 +120,17 @@ pub struct Server {
     pub identifier: String,
     pub addresses: Vec<String>,
     pub data_dir: String,
+    #[serde(default)]
     pub is_provider: bool,
+    #[serde(default = "default_num_workers")]
     pub num_wire_workers: u16,
+    #[serde(default = "default_num_workers")]
     pub num_sphinx_workers: u16,
+    #[serde(default = "default_num_workers")]
     pub num_crypto_workers: u16,
+    #[serde(default = "default_slack_time")]
     pub crypto_worker_slack_time: u64,
+    #[serde(default = "default_line_rate")]
     pub line_rate: u64,
 }
 
@@ -45,6 +138,10 @@ pub struct Server {
 pub struct Nonvoting {
     pub address: String,
     pub public_key: String,
+    /// Absent from configurations written before authorities were
+    /// reached over the wire protocol, `validate` reports it missing.
+    #[serde(default)]
+    pub link_public_key: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -66,14 +163,264 @@ pub struct Pki {
     pub voting: Option<Voting>,
 }
 
+/// CoDel parameters for the crypto worker queue, in milliseconds.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Aqm {
+    pub target: u64,
+    pub interval: u64,
+}
+
+impl Default for Aqm {
+    fn default() -> Aqm {
+        Aqm {
+            target: aqm::DEFAULT_TARGET,
+            interval: aqm::DEFAULT_INTERVAL,
+        }
+    }
+}
+
+/// Prometheus metrics endpoint, listening on `address`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Metrics {
+    pub address: String,
+}
+
+/// Decoy loop traffic sent by mixes. `rate` is in packets per
+/// second, `mean_delay` is the mean per hop delay in milliseconds.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Decoy {
+    pub rate: f64,
+    pub mean_delay: u64,
+}
+
+/// Packet lifecycle tracing for debugging, `sample_rate` is the
+/// fraction of received packets traced. Do not enable in production.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Tracing {
+    pub sample_rate: f64,
+}
+
+/// An external Kaetzchen service run as a subprocess of a provider
+/// and addressed by the recipient `name`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Plugin {
+    pub name: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default = "default_plugin_concurrency")]
+    pub max_concurrency: usize,
+}
+
+/// A client allowed to connect to a provider with the given link key.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Client {
+    pub name: String,
+    pub link_public_key: String,
+}
+
+/// What to do with packets still queued when the server shuts down.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum QueuePolicy {
+    /// Keep processing queued packets until `drain_timeout` expires.
+    Drain,
+    /// Discard queued packets immediately.
+    Drop,
+}
+
+/// Shutdown behavior, `drain_timeout` is in milliseconds.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Shutdown {
+    pub queue_policy: QueuePolicy,
+    pub drain_timeout: u64,
+}
+
+impl Default for Shutdown {
+    fn default() -> Shutdown {
+        Shutdown {
+            queue_policy: QueuePolicy::Drain,
+            drain_timeout: 10_000,
+        }
+    }
+}
+
+impl Shutdown {
+    /// Returns the instant by which draining workers must give up,
+    /// or None if queued packets are to be dropped.
+    pub fn drain_deadline(&self) -> Option<Instant> {
+        match self.queue_policy {
+            QueuePolicy::Drain => Some(Instant::now() + Duration::from_millis(self.drain_timeout)),
+            QueuePolicy::Drop => None,
+        }
+    }
+
+    /// Hand the items still queued on `rx` to `handle` until every
+    /// sender has gone away or the drain timeout expires, unless the
+    /// policy is to drop them. `handle` returns false to give up.
+    pub fn drain<T, F>(&self, worker: &str, rx: &Receiver<T>, mut handle: F) where F: FnMut(T) -> bool {
+        let deadline = match self.drain_deadline() {
+            Some(x) => x,
+            None => return,
+        };
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break
+            }
+            match rx.recv_timeout(deadline - now) {
+                Ok(item) => {
+                    if !handle(item) {
+                        return
+                    }
+                },
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+        drain_timed_out(worker, rx.len());
+    }
+}
+
+/// Report the items a worker drops because its drain timed out.
+pub fn drain_timed_out(worker: &str, dropped: usize) {
+    if dropped > 0 {
+        info!("{} drain timed out, dropping {} queued packets", worker, dropped);
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
+    #[serde(default)]
     pub logging: Logging,
     pub server: Server,
     pub pki: Pki,
+    pub aqm: Option<Aqm>,
+    pub shutdown: Option<Shutdown>,
+    pub metrics: Option<Metrics>,
+    pub decoy: Option<Decoy>,
+    pub tracing: Option<Tracing>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub plugins: Vec<Plugin>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub clients: Vec<Client>,
+}
+
+/// Describes a configuration field in the file written by `Config::store`.
+fn field_comment(section: &str, key: &str) -> Option<&'static str> {
+    let comment = match (section, key) {
+        ("logging", "disable") => "Disables logging entirely.",
+        ("logging", "log_file") => "Path of the log file, logs to stderr if empty.",
+        ("logging", "level") => "One of OFF, ERROR, WARN, INFO, DEBUG or TRACE.",
+        ("logging", "max_file_size") => "Rotate the log file once it reaches this many bytes, 0 disables rotation.",
+        ("logging", "max_files") => "Number of rotated log files to keep.",
+        ("logging", "modules") => "Log levels overriding `level` for single modules, as module = \"LEVEL\".",
+        ("server", "identifier") => "Human readable name of this node, published in its descriptor.",
+        ("server", "addresses") => "Addresses to listen on, as IP:port.",
+        ("server", "data_dir") => "Directory holding the keys, replay caches and spool. Must exist, relative paths are from the working directory.",
+        ("server", "is_provider") => "Run as a provider instead of a mix.",
+        ("server", "num_wire_workers") => "Number of wire protocol workers, defaults to the number of CPUs.",
+        ("server", "num_sphinx_workers") => "Number of Sphinx workers, defaults to the number of CPUs.",
+        ("server", "num_crypto_workers") => "Number of crypto workers, defaults to the number of CPUs.",
+        ("server", "crypto_worker_slack_time") => "Upper bound in milliseconds on the wait for a crypto worker, enforced regardless of the AQM.",
+        ("server", "line_rate") => "Expected packets per second, used to size the replay caches.",
+        ("pki.nonvoting", "address") => "Address of the nonvoting authority, as host:port.",
+        ("pki.nonvoting", "public_key") => "Base64 identity public key of the authority. Replace the all zero placeholder with the real key.",
+        ("pki.nonvoting", "link_public_key") => "Base64 link public key of the authority. Replace the all zero placeholder with the real key.",
+        ("pki.voting", "epoch_duration") => "Epoch duration in seconds, only the katzenpost epoch of 10800 is supported.",
+        ("pki.voting.peers", "addresses") => "Addresses of this authority, as host:port.",
+        ("pki.voting.peers", "identity_public_key") => "Base64 identity public key of this authority.",
+        ("pki.voting.peers", "link_public_key") => "Base64 link public key of this authority.",
+        ("aqm", "target") => "CoDel target queue delay in milliseconds.",
+        ("aqm", "interval") => "CoDel interval in milliseconds.",
+        ("shutdown", "queue_policy") => "Drain or Drop the queued packets on shutdown.",
+        ("shutdown", "drain_timeout") => "Milliseconds to spend draining queues before dropping the rest.",
+        ("decoy", "rate") => "Decoy loop packets sent per second, mixes only.",
+        ("decoy", "mean_delay") => "Mean per hop delay of decoy packets in milliseconds.",
+        ("tracing", "sample_rate") => "Fraction of packets whose lifecycle is logged, leaks timing data.",
+        ("plugins", "name") => "Recipient name the plugin is reachable as, providers only.",
+        ("plugins", "command") => "Path of the plugin executable.",
+        ("plugins", "args") => "Arguments passed to the plugin.",
+        ("plugins", "max_concurrency") => "Requests the plugin may be handling at once, more are dropped.",
+        ("clients", "name") => "Name of the client, for the operator's reference.",
+        ("clients", "link_public_key") => "Base64 link public key the client connects with, providers only.",
+        ("metrics", "address") => "Local address serving Prometheus metrics, remove the section to disable.",
+        _ => return None,
+    };
+    Some(comment)
+}
+
+/// Prefix each field of a serialized configuration with its description.
+fn annotate(toml_config: &str) -> String {
+    let mut annotated = String::from("# Mix server configuration.\n\n");
+    let mut section = String::new();
+    for line in toml_config.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            section = trimmed.trim_matches(|c| c == '[' || c == ']').to_string();
+            // Tables nested in a section are described like its fields.
+            if let Some(i) = section.rfind('.') {
+                if let Some(comment) = field_comment(&section[..i], &section[i + 1..]) {
+                    annotated.push_str("# ");
+                    annotated.push_str(comment);
+                    annotated.push('\n');
+                }
+            }
+        } else if let Some(i) = trimmed.find(" = ") {
+            if let Some(comment) = field_comment(&section, &trimmed[..i]) {
+                annotated.push_str("# ");
+                annotated.push_str(comment);
+                annotated.push('\n');
+            }
+        }
+        annotated.push_str(line);
+        annotated.push('\n');
+    }
+    annotated
 }
 
 impl Config {
+    /// Returns the example configuration written by `--generate-config`.
+    /// The PKI keys are all zero placeholders which fail validation
+    /// until they are replaced by the authority's keys.
+    pub fn example() -> Config {
+        let mut logging = Logging::default();
+        logging.modules.insert("sled".to_string(), "WARN".to_string());
+        Config {
+            logging: logging,
+            server: Server {
+                identifier: "example-mix".to_string(),
+                addresses: vec!["0.0.0.0:29483".to_string()],
+                data_dir: ".".to_string(),
+                is_provider: false,
+                num_wire_workers: default_num_workers(),
+                num_sphinx_workers: default_num_workers(),
+                num_crypto_workers: default_num_workers(),
+                crypto_worker_slack_time: DEFAULT_SLACK_TIME,
+                line_rate: DEFAULT_LINE_RATE,
+            },
+            pki: Pki {
+                nonvoting: Some(Nonvoting {
+                    address: "127.0.0.1:29484".to_string(),
+                    public_key: PLACEHOLDER_KEY.to_string(),
+                    link_public_key: PLACEHOLDER_KEY.to_string(),
+                }),
+                voting: None,
+            },
+            aqm: Some(Aqm::default()),
+            shutdown: Some(Shutdown::default()),
+            metrics: Some(Metrics {
+                address: "127.0.0.1:9100".to_string(),
+            }),
+            decoy: Some(Decoy {
+                rate: 0.1,
+                mean_delay: 100,
+            }),
+            tracing: None,
+            plugins: vec![],
+            clients: vec![],
+        }
+    }
+
     pub fn load(contents: String) -> Result<Config, ConfigError> {
         let config: Config = toml::from_str(&contents)?;
         Ok(config)
@@ -86,10 +433,370 @@ impl Config {
         Ok(Config::load(contents)?)
     }
 
+    /// Check the configuration for semantic errors, every problem
+    /// found is reported along with the path of the offending field.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = vec![];
+
+        if self.logging.level.parse::<LevelFilter>().is_err() {
+            errors.push(ConfigError::UnknownLogLevel("logging.level".to_string(), self.logging.level.clone()));
+        }
+        for (module, level) in self.logging.modules.iter() {
+            if level.parse::<LevelFilter>().is_err() {
+                errors.push(ConfigError::UnknownLogLevel(format!("logging.modules.\"{}\"", module), level.clone()));
+            }
+        }
+
+        if self.server.addresses.is_empty() {
+            errors.push(ConfigError::InvalidAddress("server.addresses".to_string(), String::new()));
+        }
+        for (i, address) in self.server.addresses.iter().enumerate() {
+            if address.parse::<SocketAddr>().is_err() {
+                errors.push(ConfigError::InvalidAddress(format!("server.addresses[{}]", i), address.clone()));
+            }
+        }
+        if let Some(ref metrics) = self.metrics {
+            if metrics.address.parse::<SocketAddr>().is_err() {
+                errors.push(ConfigError::InvalidAddress("metrics.address".to_string(), metrics.address.clone()));
+            }
+        }
+        if self.server.data_dir.is_empty() || !Path::new(&self.server.data_dir).is_dir() {
+            errors.push(ConfigError::MissingDataDir(self.server.data_dir.clone()));
+        }
+        let worker_counts = [
+            ("server.num_wire_workers", self.server.num_wire_workers),
+            ("server.num_sphinx_workers", self.server.num_sphinx_workers),
+            ("server.num_crypto_workers", self.server.num_crypto_workers),
+        ];
+        for &(field, count) in worker_counts.iter() {
+            if count == 0 {
+                errors.push(ConfigError::ZeroWorkers(field.to_string()));
+            }
+        }
+
+        if let Some(ref tracing) = self.tracing {
+            if !(tracing.sample_rate > 0.0 && tracing.sample_rate <= 1.0) {
+                errors.push(ConfigError::InvalidSampleRate(tracing.sample_rate));
+            }
+        }
+        if let Some(ref decoy) = self.decoy {
+            if !(decoy.rate.is_finite() && decoy.rate >= 0.0) {
+                errors.push(ConfigError::InvalidDecoyRate(decoy.rate));
+            }
+        }
+        // Recipient names are matched case insensitively.
+        let mut plugin_names = vec![];
+        for (i, plugin) in self.plugins.iter().enumerate() {
+            let name = plugin.name.to_ascii_lowercase();
+            if name.is_empty() {
+                errors.push(ConfigError::EmptyField(format!("plugins[{}].name", i)));
+            } else if name == ECHO_SERVICE || name == LOOP_SERVICE {
+                errors.push(ConfigError::ReservedName(format!("plugins[{}].name", i), plugin.name.clone()));
+            } else if plugin_names.contains(&name) {
+                errors.push(ConfigError::DuplicateName(format!("plugins[{}].name", i), plugin.name.clone()));
+            }
+            plugin_names.push(name);
+            if plugin.command.is_empty() {
+                errors.push(ConfigError::EmptyField(format!("plugins[{}].command", i)));
+            }
+            if plugin.max_concurrency == 0 {
+                errors.push(ConfigError::ZeroConcurrency(format!("plugins[{}].max_concurrency", i)));
+            }
+        }
+        for (i, client) in self.clients.iter().enumerate() {
+            validate_link_key(&format!("clients[{}].link_public_key", i), &client.link_public_key, &mut errors);
+        }
+
+        match (&self.pki.nonvoting, &self.pki.voting) {
+            (Some(_), Some(_)) => errors.push(ConfigError::AmbiguousPki),
+            (None, None) => errors.push(ConfigError::NoPki),
+            (Some(nonvoting), None) => {
+                validate_authority_address("pki.nonvoting.address", &nonvoting.address, &mut errors);
+                validate_identity_key("pki.nonvoting.public_key", &nonvoting.public_key, &mut errors);
+                if nonvoting.link_public_key.is_empty() {
+                    errors.push(ConfigError::EmptyField("pki.nonvoting.link_public_key".to_string()));
+                } else {
+                    validate_link_key("pki.nonvoting.link_public_key", &nonvoting.link_public_key, &mut errors);
+                }
+            },
+            (None, Some(voting)) => {
+                // Every worker runs on the katzenpost clock.
+                if voting.epoch_duration != constants::EPOCH_DURATION {
+                    errors.push(ConfigError::UnsupportedEpochDuration(voting.epoch_duration));
+                }
+                if voting.peers.is_empty() {
+                    errors.push(ConfigError::NoPki);
+                }
+                for (i, peer) in voting.peers.iter().enumerate() {
+                    for (j, address) in peer.addresses.iter().enumerate() {
+                        validate_authority_address(&format!("pki.voting.peers[{}].addresses[{}]", i, j), address, &mut errors);
+                    }
+                    validate_identity_key(&format!("pki.voting.peers[{}].identity_public_key", i),
+                                          &peer.identity_public_key, &mut errors);
+                    validate_link_key(&format!("pki.voting.peers[{}].link_public_key", i),
+                                      &peer.link_public_key, &mut errors);
+                }
+            },
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(errors))
+        }
+    }
+
     pub fn store(&self, file_name: String) -> Result<(), ConfigError> {
+        let toml_config = toml::to_string(&self)?;
         let mut file = File::create(file_name)?;
-        let toml_config = toml::to_string(&self).unwrap();
-        file.write_all(toml_config.as_bytes())?;
+        file.write_all(annotate(&toml_config).as_bytes())?;
         Ok(())
     }
 }
+
+/// Authorities may be given by host name, so only the
+/// `host:port` form is checked here.
+fn validate_authority_address(field: &str, address: &str, errors: &mut Vec<ConfigError>) {
+    let valid = match address.rfind(':') {
+        Some(i) => i > 0 && address[i + 1..].parse::<u16>().is_ok(),
+        None => false,
+    };
+    if !valid {
+        errors.push(ConfigError::InvalidAddress(field.to_string(), address.to_string()));
+    }
+}
+
+/// Returns true for a key left at the all zero placeholder
+/// of the example configuration.
+fn is_placeholder_key(encoded: &str) -> bool {
+    match encoded.from_base64() {
+        Ok(raw) => raw.iter().all(|x| *x == 0),
+        Err(_) => false,
+    }
+}
+
+fn validate_identity_key(field: &str, encoded: &str, errors: &mut Vec<ConfigError>) {
+    if is_placeholder_key(encoded) {
+        errors.push(ConfigError::PlaceholderKey(field.to_string()));
+    } else if identity_key_from_base64(encoded).is_err() {
+        errors.push(ConfigError::InvalidPublicKey(field.to_string()));
+    }
+}
+
+fn validate_link_key(field: &str, encoded: &str, errors: &mut Vec<ConfigError>) {
+    if is_placeholder_key(encoded) {
+        errors.push(ConfigError::PlaceholderKey(field.to_string()));
+    } else if link_key_from_base64(encoded).is_err() {
+        errors.push(ConfigError::InvalidPublicKey(field.to_string()));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use crossbeam_channel::unbounded;
+    use super::*;
+
+    fn config_text(data_dir: &str) -> String {
+        format!(r#"
+[logging]
+disable = false
+log_file = "{0}/mix.log"
+level = "loud"
+
+[server]
+identifier = "mix1"
+addresses = ["127.0.0.1:29483", "not an address"]
+data_dir = "{0}"
+is_provider = false
+num_wire_workers = 0
+num_sphinx_workers = 1
+num_crypto_workers = 1
+crypto_worker_slack_time = 100
+line_rate = 1000
+
+[pki]
+"#, data_dir)
+    }
+
+    #[test]
+    fn config_store_roundtrip_test() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("mix.toml");
+        Config::example().store(path.to_str().unwrap().to_string()).unwrap();
+        let cfg = Config::load_file(path.to_str().unwrap().to_string()).unwrap();
+        assert_eq!(cfg.server.identifier, "example-mix");
+        assert_eq!(cfg.server.num_crypto_workers, default_num_workers());
+        assert!(cfg.aqm.is_some());
+        let mut contents = String::new();
+        File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        assert!(contents.contains("# Log levels overriding"));
+
+        // The placeholder authority keys are all that is left to fill in.
+        let errors = match cfg.validate() {
+            Err(ConfigError::Invalid(errors)) => errors,
+            _ => panic!("placeholder keys passed validation"),
+        };
+        let messages: Vec<String> = errors.iter().map(|x| x.to_string()).collect();
+        assert_eq!(messages, vec![
+            ConfigError::PlaceholderKey("pki.nonvoting.public_key".to_string()).to_string(),
+            ConfigError::PlaceholderKey("pki.nonvoting.link_public_key".to_string()).to_string(),
+        ]);
+
+        let minimal = Config::load(r#"
+[server]
+identifier = "mix1"
+addresses = ["127.0.0.1:29483"]
+data_dir = "/tmp"
+
+[pki]
+"#.to_string()).unwrap();
+        assert_eq!(minimal.logging.level, DEFAULT_LOG_LEVEL);
+        assert_eq!(minimal.server.crypto_worker_slack_time, DEFAULT_SLACK_TIME);
+        assert!(minimal.server.num_wire_workers >= 1);
+    }
+
+    #[test]
+    fn config_validate_test() {
+        let dir = TempDir::new().unwrap();
+        let cfg = Config::load(config_text(dir.path().to_str().unwrap())).unwrap();
+        let errors = match cfg.validate() {
+            Err(ConfigError::Invalid(errors)) => errors,
+            _ => panic!("invalid config passed validation"),
+        };
+        let messages: Vec<String> = errors.iter().map(|x| x.to_string()).collect();
+        assert_eq!(errors.len(), 4);
+        assert!(messages[0].starts_with("logging.level"));
+        assert!(messages[1].starts_with("server.addresses[1]"));
+        assert!(messages[2].starts_with("server.num_wire_workers"));
+        match errors[3] {
+            ConfigError::NoPki => {},
+            _ => panic!("missing PKI not reported"),
+        }
+    }
+
+    #[test]
+    fn config_plugins_test() {
+        let dir = TempDir::new().unwrap();
+        let text = config_text(dir.path().to_str().unwrap()) + r#"
+[[plugins]]
+name = "Echo"
+command = "/bin/cat"
+
+[[plugins]]
+name = "weather"
+command = "/bin/cat"
+max_concurrency = 0
+
+[[plugins]]
+name = "Weather"
+command = "/bin/cat"
+"#;
+        let cfg = Config::load(text).unwrap();
+        let errors = match cfg.validate() {
+            Err(ConfigError::Invalid(errors)) => errors,
+            _ => panic!("invalid config passed validation"),
+        };
+        let messages: Vec<String> = errors.into_iter().filter_map(|x| match x {
+            ConfigError::ReservedName(..) |
+            ConfigError::DuplicateName(..) |
+            ConfigError::ZeroConcurrency(..) => Some(x.to_string()),
+            _ => None,
+        }).collect();
+        assert_eq!(messages.len(), 3);
+        assert!(messages[0].starts_with("plugins[0].name"));
+        assert!(messages[1].starts_with("plugins[1].max_concurrency"));
+        assert!(messages[2].starts_with("plugins[2].name"));
+    }
+
+    #[test]
+    fn config_missing_link_key_test() {
+        let dir = TempDir::new().unwrap();
+        let text = config_text(dir.path().to_str().unwrap()) + r#"
+[pki.nonvoting]
+address = "127.0.0.1:29484"
+public_key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+"#;
+        let cfg = Config::load(text).unwrap();
+        assert!(cfg.pki.nonvoting.as_ref().unwrap().link_public_key.is_empty());
+        let errors = match cfg.validate() {
+            Err(ConfigError::Invalid(errors)) => errors,
+            _ => panic!("invalid config passed validation"),
+        };
+        assert!(errors.iter().any(|x| match x {
+            ConfigError::EmptyField(field) => field == "pki.nonvoting.link_public_key",
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn config_voting_epoch_test() {
+        let dir = TempDir::new().unwrap();
+        let voting = |duration: u64| format!(r#"
+[pki.voting]
+epoch_duration = {}
+
+[[pki.voting.peers]]
+addresses = ["127.0.0.1:29484"]
+identity_public_key = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE="
+link_public_key = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE="
+"#, duration);
+        let epoch_errors = |duration: u64| {
+            let cfg = Config::load(config_text(dir.path().to_str().unwrap()) + &voting(duration)).unwrap();
+            match cfg.validate() {
+                Err(ConfigError::Invalid(errors)) => errors.into_iter().filter(|x| match x {
+                    ConfigError::UnsupportedEpochDuration(_) => true,
+                    _ => false,
+                }).count(),
+                _ => panic!("invalid config passed validation"),
+            }
+        };
+        assert_eq!(epoch_errors(constants::EPOCH_DURATION), 0);
+        assert_eq!(epoch_errors(600), 1);
+    }
+
+    #[test]
+    fn config_decoy_rate_test() {
+        let dir = TempDir::new().unwrap();
+        let mut cfg = Config::load(config_text(dir.path().to_str().unwrap())).unwrap();
+        let mut rate_errors = |rate: f64| {
+            cfg.decoy = Some(Decoy { rate: rate, mean_delay: 0 });
+            match cfg.validate() {
+                Err(ConfigError::Invalid(errors)) => errors.into_iter().filter(|x| match x {
+                    ConfigError::InvalidDecoyRate(_) => true,
+                    _ => false,
+                }).count(),
+                _ => panic!("invalid config passed validation"),
+            }
+        };
+        assert_eq!(rate_errors(0.0), 0);
+        assert_eq!(rate_errors(0.5), 0);
+        assert_eq!(rate_errors(-1.0), 1);
+        assert_eq!(rate_errors(::std::f64::NAN), 1);
+        assert_eq!(rate_errors(::std::f64::INFINITY), 1);
+    }
+
+    #[test]
+    fn shutdown_drain_test() {
+        let (tx, rx) = unbounded();
+        for i in 0..3 {
+            tx.send(i).unwrap();
+        }
+        drop(tx);
+        let mut drained = vec![];
+        Shutdown {
+            queue_policy: QueuePolicy::Drop,
+            drain_timeout: 1000,
+        }.drain("test", &rx, |x| { drained.push(x); true });
+        assert!(drained.is_empty());
+
+        // Draining stops early when the handler gives up.
+        Shutdown::default().drain("test", &rx, |x| { drained.push(x); x < 1 });
+        assert_eq!(drained, vec![0, 1]);
+        Shutdown::default().drain("test", &rx, |x| { drained.push(x); true });
+        assert_eq!(drained, vec![0, 1, 2]);
+    }
+}
@@ -18,5 +18,21 @@
 pub const NUM_MIX_KEYS: u8 = 3;
 
 
-/// The number
+/// The epoch duration in seconds of the katzenpost clock,
+/// the only epoch the server's workers run on.
+pub const EPOCH_DURATION: u64 = 3 * 60 * 60;
+
+/// The number of seconds at either end of an epoch during which
+/// the key of the adjacent epoch is also used to unwrap packets.
 pub const GRACE_PERIOD: u64 = 3;
+
+
+/// The size of the plaintext header prepended to forward payloads.
+pub const SPHINX_PLAINTEXT_HEADER_SIZE: usize = 2;
+
+/// Forward payload header flag indicating a SURB is attached.
+pub const SURB_FLAG: u8 = 1;
+
+
+/// Milliseconds to wait for an outgoing TCP connection to be established.
+pub const CONNECT_TIMEOUT: u64 = 10_000;
@@ -22,36 +22,56 @@ extern crate sphinx_replay_cache;
 extern crate sphinxcrypto;
 
 use std::thread;
+use std::thread::JoinHandle;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::collections::HashMap;
 
 use epoch::Clock;
-use crossbeam_channel::{Receiver, Select};
+use crossbeam_channel::{Receiver, Sender, Select};
 use sphinx_replay_cache::{MixKeys, MixKey, Tag};
 use sphinxcrypto::server::sphinx_packet_unwrap;
 
-use super::packet::Packet;
+use super::packet::{Packet, PacketKind};
+use super::aqm::Codel;
+use super::config::Shutdown;
+use super::metrics::Metrics;
+use super::drops::DropReason;
+use super::trace::{Tracer, TraceEvent};
+use super::kaetzchen::KaetzchenRegistry;
+use super::spool::normalize_recipient;
 use super::errors::UnwrapPacketError;
 use super::constants;
 
 
 pub struct CryptoWorkerConfig {
     pub crypto_worker_rx: Receiver<Packet>,
+    pub scheduler_tx: Sender<Packet>,
+    pub provider_tx: Sender<Packet>,
     pub update_rx: Receiver<bool>,
     pub halt_rx: Receiver<bool>,
     pub slack_time: u64,
+    pub aqm: Codel,
     pub clock: Clock,
     pub mix_keys: MixKeys,
     pub is_provider: bool,
+    pub shutdown: Shutdown,
+    pub metrics: Metrics,
+    pub decoy_tx: Option<Sender<Packet>>,
+    pub kaetzchen: KaetzchenRegistry,
+    pub kaetzchen_tx: Sender<Packet>,
+    pub tracer: Tracer,
 }
 
-pub fn start_crypto_worker(cfg: CryptoWorkerConfig) {
+pub fn start_crypto_worker(cfg: CryptoWorkerConfig) -> JoinHandle<()> {
     thread::spawn(move || {
         crypto_worker(cfg)
-    });
+    })
 }
 
-fn unwrap_packet(packet: &mut Packet, clock: &Clock, shadow_mix_keys: &mut HashMap<u64, MixKey>) -> Result<(),UnwrapPacketError>{
+/// Returns the epoch of the key which unwrapped the packet. The
+/// candidate keys are tried in turn and the first one to unwrap the
+/// packet wins, `DecryptFail` means none of them could.
+fn unwrap_packet(packet: &mut Packet, clock: &Clock, shadow_mix_keys: &mut HashMap<u64, MixKey>) -> Result<u64, UnwrapPacketError>{
     // Figure out the candidate mix private keys for this packet.
     let time = clock.now();
     let mut epochs: Vec<u64> = vec![];
@@ -97,121 +117,347 @@ fn unwrap_packet(packet: &mut Packet, clock: &Clock, shadow_mix_keys: &mut HashM
         if let Some(commands) = cmds {
             packet.set_commands(commands);
         }
+        return Ok(*epoch)
     }
-    Ok(())
+    Err(UnwrapPacketError::DecryptFail)
+}
+
+fn unwrap_result_label(result: &Result<u64, UnwrapPacketError>) -> &'static str {
+    match result {
+        Ok(_) => "ok",
+        Err(UnwrapPacketError::NoKey) => "no_key",
+        Err(UnwrapPacketError::CacheFail) => "cache_fail",
+        Err(UnwrapPacketError::Replay) => "replay",
+        Err(UnwrapPacketError::DecryptFail) => "decrypt_fail",
+    }
+}
+
+fn drop_packet(cfg: &CryptoWorkerConfig, packet: &Packet, reason: DropReason) {
+    cfg.metrics.drops().report(reason);
+    cfg.tracer.record(packet, TraceEvent::Dropped(reason));
 }
 
 fn crypto_worker(cfg: CryptoWorkerConfig) {
-    let absolute_minimum_delay = Duration::from_millis(1);
     let mut shadow_mix_keys: HashMap<u64, MixKey> = HashMap::new();
-    let clock = &cfg.clock;
     let mut sel = Select::new();
     let oper1 = sel.recv(&cfg.crypto_worker_rx);
     let oper2 = sel.recv(&cfg.update_rx);
     let oper3 = sel.recv(&cfg.halt_rx);
     loop {
-        let mut packet = Packet::default();
         let oper = sel.select();
         match oper.index() {
             i if i == oper1 => {
-                packet = match oper.recv(&cfg.crypto_worker_rx) {
+                let packet = match oper.recv(&cfg.crypto_worker_rx) {
                     Ok(x) => x,
-                    Err(e) => {
-                        warn!("crypto worker failed to receive packet: {}", e);
+                    Err(_) => {
+                        debug!("crypto worker queue closed, halting.");
                         return
                     },
                 };
+                if !handle_packet(&cfg, &mut shadow_mix_keys, packet) {
+                    return
+                }
             },
             i if i == oper2 => {
-                if let Err(e) = oper.recv(&cfg.update_rx) {
-                    warn!("failed to receive on update chan: {}", e);
-                    return
+                if oper.recv(&cfg.update_rx).is_err() {
+                    // Keep serving with the keys we have,
+                    // shutdown is signaled on the halt channel.
+                    warn!("crypto worker lost the key manager, mix keys will no longer be refreshed");
+                    sel.remove(oper2);
+                    continue
                 }
                 let mut mix_keys = cfg.mix_keys.clone();
                 mix_keys.shadow(&mut shadow_mix_keys);
-                continue
             },
             i if i == oper3 => {
-                oper.recv(&cfg.halt_rx).unwrap();
+                // The halt channel is closed to signal shutdown.
+                let _ = oper.recv(&cfg.halt_rx);
+                // Keep processing queued packets until the wire
+                // workers have all gone away and the queue is empty.
+                cfg.shutdown.drain("crypto worker", &cfg.crypto_worker_rx,
+                                   |packet| handle_packet(&cfg, &mut shadow_mix_keys, packet));
                 return
             },
             _ => unreachable!(),
         }
+    }
+}
 
-        // Drop the packet if it has been sitting in the queue waiting to
-	// be decrypted for way too long.
-        let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
-            Ok(x) => x,
-            Err(e) => {
-                warn!("crypto worker failed to read time from clock: {}", e);
-                return
-            },
-        };
-        let dwell_time = now - Duration::from_millis(packet.receive_time);
-        if dwell_time > Duration::from_millis(cfg.slack_time) {
-            debug!("dropping packet, dwelled too long.");
-            continue
-        } else {
-            debug!("crypto worker packet queue delay {:?}", dwell_time);
-        }
+/// Unwrap a single packet and hand it off to the next stage.
+/// Returns false if the worker can no longer make progress.
+fn handle_packet(cfg: &CryptoWorkerConfig, shadow_mix_keys: &mut HashMap<u64, MixKey>, mut packet: Packet) -> bool {
+    let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("crypto worker failed to read time from clock: {}", e);
+            return false
+        },
+    };
+    let dwell_time = now - Duration::from_millis(packet.receive_time);
+    let queue_len = cfg.crypto_worker_rx.len();
+    cfg.metrics.set_crypto_queue_depth(queue_len);
+    cfg.metrics.observe_dwell_time(dwell_time.as_millis() as u64);
+
+    // Let the AQM decide whether the queue is congested, it sees
+    // every packet's dwell time including the ones dropped below.
+    if cfg.aqm.should_drop(dwell_time.as_millis() as u64, now.as_millis() as u64, queue_len) {
+        drop_packet(cfg, &packet, DropReason::Congestion);
+        return true
+    }
+
+    // The slack time is an upper bound on queueing independent of
+    // the AQM, a packet this late can no longer honor its delay.
+    if dwell_time > Duration::from_millis(cfg.slack_time) {
+        drop_packet(cfg, &packet, DropReason::DwellTime);
+        return true
+    }
+    debug!("crypto worker packet queue delay {:?}", dwell_time);
 
-	// Attempt to unwrap the packet.
-        if let Err(e) = unwrap_packet(&mut packet, clock, &mut shadow_mix_keys) {
+    // Attempt to unwrap the packet.
+    let result = unwrap_packet(&mut packet, &cfg.clock, shadow_mix_keys);
+    cfg.metrics.unwrap_result(unwrap_result_label(&result));
+    match result {
+        Ok(epoch) => cfg.tracer.record(&packet, TraceEvent::Unwrapped(epoch)),
+        Err(e) => {
             warn!("failed to unwrap packet: {}", e);
-            continue
-        }
+            drop_packet(cfg, &packet, DropReason::UnwrapFailed);
+            return true
+        },
+    }
+    let kind = match packet.classify() {
+        Ok(x) => x,
+        Err(e) => {
+            debug!("packet has invalid routing commands: {}", e);
+            PacketKind::Invalid
+        },
+    };
+    cfg.tracer.record(&packet, TraceEvent::Classified(kind.as_str()));
 
+    match kind {
         // Route the packet to another mix.
-        if packet.is_forward() {
-            if packet.must_terminate {
-                debug!("Dropping packet: (Provider received forward packet from mix)");
-                continue
+        PacketKind::Forward => schedule_forward(cfg, packet, dwell_time),
+        // This may be a decoy traffic response.
+        PacketKind::SurbReply if !cfg.is_provider => {
+            match cfg.decoy_tx {
+                Some(ref decoy_tx) => {
+                    debug!("Handing off decoy response packet");
+                    cfg.tracer.record(&packet, TraceEvent::Dispatched);
+                    if let Err(e) = decoy_tx.send(packet) {
+                        drop_packet(cfg, &e.0, DropReason::DecoyResponse);
+                    }
+                },
+                None => drop_packet(cfg, &packet, DropReason::DecoyResponse),
             }
-
-	    // Check and adjust the delay for queue dwell time.
-            let delay = packet.delay_cmd.clone().unwrap().delay as u64;
-            let packet_delay = Duration::from_millis(delay);
-            if packet_delay > dwell_time {
-                packet.delay = delay - dwell_time.as_millis() as u64;
-            } else if delay == 0 {
-                if dwell_time < absolute_minimum_delay {
-                    let delta = absolute_minimum_delay - dwell_time;
-                    packet.delay = delta.as_millis() as u64;
-                } else {
-                    debug!("Dropping packet: (delay: {:?})", dwell_time);
-                    continue
-                }
-            } else {
-                packet.delay = absolute_minimum_delay.as_millis() as u64;
+            true
+        },
+        _ if !cfg.is_provider => {
+            drop_packet(cfg, &packet, DropReason::InvalidMixPacket);
+            true
+        },
+        // This node is a provider and the packet is not destined for another
+        // node.  Both of the operations here end up hitting up disk among
+        // other things, so are just shunted off to a separate worker so that
+        // packet processing does not get blocked.
+        _ if packet.must_forward => {
+            drop_packet(cfg, &packet, DropReason::ClientPacket);
+            true
+        },
+        PacketKind::Invalid => {
+            drop_packet(cfg, &packet, DropReason::InvalidUserPacket);
+            true
+        },
+        // Requests for a service hosted on this provider
+        // are handed to the kaetzchen worker.
+        PacketKind::ToUser | PacketKind::UnreliableToUser if is_service_request(cfg, &packet) => {
+            cfg.tracer.record(&packet, TraceEvent::Dispatched);
+            if let Err(e) = cfg.kaetzchen_tx.send(packet) {
+                warn!("crypto worker failed to send packet to kaetzchen worker: {}", e);
+                return false
             }
-
-            // Hand off to the scheduler.
-            debug!("Dispatching packet");
-            // XXX todo: send packet to mix strategy AQM
-        } else if !cfg.is_provider {
-	    // This may be a decoy traffic response.
-            if packet.is_surb_reply() {
-                debug!("Handing off decoy response packet");
-                // XXX decoy_fsm.on_packet(packet)...
-                continue
+            true
+        },
+        PacketKind::ToUser | PacketKind::UnreliableToUser | PacketKind::SurbReply => {
+            cfg.tracer.record(&packet, TraceEvent::Dispatched);
+            if let Err(e) = cfg.provider_tx.send(packet) {
+                warn!("crypto worker failed to send packet to provider worker: {}", e);
+                return false
             }
-            debug!("Dropping invalid mix packet.");
-            continue
+            true
+        },
+    }
+}
+
+fn is_service_request(cfg: &CryptoWorkerConfig, packet: &Packet) -> bool {
+    packet.recipient.as_ref()
+        .and_then(|x| normalize_recipient(x).ok())
+        .map_or(false, |x| cfg.kaetzchen.contains(&x))
+}
+
+/// Returns what is left of a forward packet's requested delay
+/// after the time it spent queued, at least a millisecond. Zero
+/// delay packets that dwelled longer than that are dropped.
+fn forward_delay(delay: u64, dwell_time: Duration) -> Option<u64> {
+    let absolute_minimum_delay = Duration::from_millis(1);
+    if Duration::from_millis(delay) > dwell_time {
+        Some(delay - dwell_time.as_millis() as u64)
+    } else if delay == 0 && dwell_time >= absolute_minimum_delay {
+        None
+    } else {
+        Some(absolute_minimum_delay.as_millis() as u64)
+    }
+}
+
+/// Adjust the delay of a forward packet for the time it spent
+/// queued and hand it to the scheduler.
+fn schedule_forward(cfg: &CryptoWorkerConfig, mut packet: Packet, dwell_time: Duration) -> bool {
+    if packet.must_terminate {
+        drop_packet(cfg, &packet, DropReason::ProviderForward);
+        return true
+    }
+
+    let delay = packet.delay_cmd.clone().unwrap().delay as u64;
+    packet.delay = match forward_delay(delay, dwell_time) {
+        Some(x) => x,
+        None => {
+            debug!("zero delay packet dwelled for {:?}", dwell_time);
+            drop_packet(cfg, &packet, DropReason::ZeroDelay);
+            return true
+        },
+    };
+
+    // Hand off to the scheduler.
+    debug!("Dispatching packet");
+    if let Err(e) = cfg.scheduler_tx.send(packet) {
+        warn!("crypto worker failed to send packet to scheduler: {}", e);
+        return false
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate rand;
+    extern crate tempfile;
+
+    use std::sync::Arc;
+    use self::rand::os::OsRng;
+    use self::tempfile::TempDir;
+    use crossbeam_channel::unbounded;
+    use ecdh_wrapper::{PrivateKey, PublicKey};
+    use sphinxcrypto::client::{new_packet, PathHop};
+    use sphinxcrypto::commands::{RoutingCommand, Recipient};
+    use sphinxcrypto::constants::{FORWARD_PAYLOAD_SIZE, RECIPIENT_ID_SIZE};
+    use aqm::{DEFAULT_TARGET, DEFAULT_INTERVAL};
+    use kaetzchen::{EchoService, ECHO_SERVICE};
+    use super::*;
+
+    fn sphinx_packet(rng: &mut OsRng, key: PublicKey, recipient: &[u8]) -> Vec<u8> {
+        let mut id = [0u8; RECIPIENT_ID_SIZE];
+        id[..recipient.len()].copy_from_slice(recipient);
+        let hop = PathHop {
+            id: [1u8; 32],
+            public_key: key,
+            commands: Some(vec![RoutingCommand::Recipient(Recipient { id: id })]),
+        };
+        new_packet(rng, vec![hop], vec![3u8; FORWARD_PAYLOAD_SIZE]).unwrap()
+    }
+
+    #[test]
+    fn unwrap_packet_test() {
+        let dir = TempDir::new().unwrap();
+        let mut rng = OsRng::new().unwrap();
+        let clock = Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let mut mix_keys = MixKeys::new(clock.clone(), constants::NUM_MIX_KEYS,
+                                        dir.path().to_str().unwrap().to_string(), 1000).unwrap();
+        mix_keys.generate(epoch).unwrap();
+        let raw = sphinx_packet(&mut rng, mix_keys.get_public_key(epoch).unwrap(), &[2u8; RECIPIENT_ID_SIZE]);
+
+        let mut shadow_mix_keys = HashMap::new();
+        match unwrap_packet(&mut Packet::new(&raw).unwrap(), &clock, &mut shadow_mix_keys) {
+            Err(UnwrapPacketError::NoKey) => {},
+            x => panic!("unexpected unwrap result {:?}", x),
         }
+        mix_keys.shadow(&mut shadow_mix_keys);
 
-        // This node is a provider and the packet is not destined for another
-	// node.  Both of the operations here end up hitting up disk among
-	// other things, so are just shunted off to a separate worker so that
-	// packet processing does not get blocked.
-        if packet.must_forward {
-            debug!("Dropping client packet");
-            continue
+        let mut packet = Packet::new(&raw).unwrap();
+        assert!(unwrap_packet(&mut packet, &clock, &mut shadow_mix_keys).is_ok());
+        assert_eq!(packet.payload, Some(vec![3u8; FORWARD_PAYLOAD_SIZE]));
+        assert!(packet.recipient.is_some());
+
+        match unwrap_packet(&mut Packet::new(&raw).unwrap(), &clock, &mut shadow_mix_keys) {
+            Err(UnwrapPacketError::Replay) => {},
+            x => panic!("unexpected unwrap result {:?}", x),
         }
 
-        if packet.is_to_user() || packet.is_unreliable_to_user() || packet.is_surb_reply() {
-            // XXX Provider processing of packet here.
-        } else {
-            debug!("Dropping invalid user packet.");
+        // A packet none of our keys can unwrap is an error
+        // rather than being passed on unchanged.
+        let stranger = PrivateKey::generate(&mut rng).unwrap();
+        let mut packet = Packet::new(&sphinx_packet(&mut rng, stranger.public_key(), &[2u8; RECIPIENT_ID_SIZE])).unwrap();
+        match unwrap_packet(&mut packet, &clock, &mut shadow_mix_keys) {
+            Err(UnwrapPacketError::DecryptFail) => {},
+            x => panic!("unexpected unwrap result {:?}", x),
         }
+        assert!(packet.payload.is_none());
+    }
+
+    #[test]
+    fn forward_delay_test() {
+        // Time spent queued counts toward the requested delay.
+        assert_eq!(forward_delay(100, Duration::from_millis(40)), Some(60));
+        assert_eq!(forward_delay(100, Duration::from_millis(150)), Some(1));
+        assert_eq!(forward_delay(0, Duration::from_micros(200)), Some(1));
+        assert_eq!(forward_delay(0, Duration::from_millis(2)), None);
+    }
+
+    #[test]
+    fn handle_packet_kaetzchen_test() {
+        let dir = TempDir::new().unwrap();
+        let mut rng = OsRng::new().unwrap();
+        let clock = Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let mut mix_keys = MixKeys::new(clock.clone(), constants::NUM_MIX_KEYS,
+                                        dir.path().to_str().unwrap().to_string(), 1000).unwrap();
+        mix_keys.generate(epoch).unwrap();
+        let mut shadow_mix_keys = HashMap::new();
+        mix_keys.shadow(&mut shadow_mix_keys);
+        let service_request = sphinx_packet(&mut rng, mix_keys.get_public_key(epoch).unwrap(), b"Echo");
+        let message = sphinx_packet(&mut rng, mix_keys.get_public_key(epoch).unwrap(), b"alice");
+
+        let (_, crypto_worker_rx) = unbounded();
+        let (scheduler_tx, _) = unbounded();
+        let (provider_tx, provider_rx) = unbounded();
+        let (kaetzchen_tx, kaetzchen_rx) = unbounded();
+        let (_, update_rx) = unbounded();
+        let (_, halt_rx) = unbounded();
+        let cfg = CryptoWorkerConfig {
+            crypto_worker_rx: crypto_worker_rx,
+            scheduler_tx: scheduler_tx,
+            provider_tx: provider_tx,
+            update_rx: update_rx,
+            halt_rx: halt_rx,
+            slack_time: 60_000,
+            aqm: Codel::new(DEFAULT_TARGET, DEFAULT_INTERVAL),
+            clock: clock,
+            mix_keys: mix_keys,
+            is_provider: true,
+            shutdown: Shutdown::default(),
+            metrics: Metrics::new(),
+            decoy_tx: None,
+            kaetzchen: KaetzchenRegistry::new(),
+            kaetzchen_tx: kaetzchen_tx,
+            tracer: Tracer::default(),
+        };
+        cfg.kaetzchen.register(ECHO_SERVICE, Arc::new(EchoService));
+
+        // Requests for a registered service go to the kaetzchen worker.
+        assert!(handle_packet(&cfg, &mut shadow_mix_keys, Packet::new(&service_request).unwrap()));
+        assert!(kaetzchen_rx.try_recv().is_ok());
+        assert!(provider_rx.try_recv().is_err());
+
+        // Everything else to users is spooled by the provider worker.
+        assert!(handle_packet(&cfg, &mut shadow_mix_keys, Packet::new(&message).unwrap()));
+        assert!(kaetzchen_rx.try_recv().is_err());
+        assert!(provider_rx.try_recv().is_ok());
     }
 }
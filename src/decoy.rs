@@ -0,0 +1,565 @@
+// decoy.rs - Mix decoy loop traffic.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Mixes send decoy loop packets through random paths of the
+//! consensus to a provider's loop service, which sends them back
+//! to us using the SURB carried in the payload. Matching the
+//! returning SURB replies yields per path loss and latency.
+
+extern crate crossbeam_channel;
+extern crate epoch;
+extern crate rand;
+extern crate sphinxcrypto;
+
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use byteorder::{BigEndian, ByteOrder};
+use crossbeam_channel::{Receiver, Sender, Select};
+use epoch::Clock;
+use rand::Rng;
+use rand::os::OsRng;
+use rand::distributions::{Exp, IndependentSample};
+use sphinxcrypto::client::{new_packet, new_surb, decrypt_surb_payload, PathHop};
+use sphinxcrypto::commands::{RoutingCommand, NextHop, Recipient, SURBReply, Delay};
+use sphinxcrypto::constants::{FORWARD_PAYLOAD_SIZE, MAC_SIZE, RECIPIENT_ID_SIZE, SURB_ID_SIZE, SURB_SIZE};
+
+use super::packet::Packet;
+use super::pki::{ConsensusStore, Document, MixDescriptor, public_key_from_bytes};
+use super::drops::{DropSink, DropReason};
+use super::errors::DecoyError;
+use super::constants;
+
+
+/// Recipient name of the provider service which returns decoys,
+/// providers host the echo service under it.
+pub const LOOP_SERVICE: &str = "loop";
+
+/// Milliseconds past its total path delay after which
+/// a decoy is counted as lost.
+const DECOY_SLACK: u64 = 60_000;
+
+/// Paths no decoy was sent or returned along for this
+/// many milliseconds are forgotten, one epoch.
+const PATH_STATS_TTL: u64 = constants::EPOCH_DURATION * 1000;
+
+/// Upper bound on the number of paths statistics are kept for,
+/// the least recently updated path makes room for a new one.
+const MAX_PATHS: usize = 4096;
+
+/// Loss and latency of the decoys sent along one path,
+/// latencies are in milliseconds.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PathStats {
+    pub sent: u64,
+    pub received: u64,
+    pub lost: u64,
+    pub total_latency: u64,
+}
+
+impl PathStats {
+    pub fn mean_latency(&self) -> Option<u64> {
+        if self.received == 0 {
+            None
+        } else {
+            Some(self.total_latency / self.received)
+        }
+    }
+}
+
+struct PathEntry {
+    stats: PathStats,
+    updated: Instant,
+}
+
+/// DecoyStats holds the statistics of the paths decoys have
+/// recently taken, keyed by the IDs of the nodes along the path.
+#[derive(Clone, Default)]
+pub struct DecoyStats {
+    paths: Arc<Mutex<HashMap<Vec<[u8; 32]>, PathEntry>>>,
+}
+
+impl DecoyStats {
+    pub fn new() -> DecoyStats {
+        DecoyStats::default()
+    }
+
+    fn update<F: FnOnce(&mut PathStats)>(&self, path: &[[u8; 32]], f: F) {
+        let mut paths = self.paths.lock().unwrap();
+        let now = Instant::now();
+        if paths.len() >= MAX_PATHS && !paths.contains_key(path) {
+            let oldest = paths.iter()
+                .min_by_key(|&(_, x)| x.updated)
+                .map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                paths.remove(&oldest);
+            }
+        }
+        let entry = paths.entry(path.to_vec()).or_insert_with(|| PathEntry {
+            stats: PathStats::default(),
+            updated: now,
+        });
+        entry.updated = now;
+        f(&mut entry.stats);
+    }
+
+    /// Forget the paths which were not updated since `now` minus `PATH_STATS_TTL`.
+    fn prune(&self, now: Instant) {
+        let ttl = Duration::from_millis(PATH_STATS_TTL);
+        self.paths.lock().unwrap().retain(|_, x| x.updated + ttl > now);
+    }
+
+    pub fn snapshot(&self) -> HashMap<Vec<[u8; 32]>, PathStats> {
+        self.paths.lock().unwrap().iter()
+            .map(|(path, x)| (path.clone(), x.stats.clone()))
+            .collect()
+    }
+}
+
+pub struct DecoyConfig {
+    pub decoy_rx: Receiver<Packet>,
+    pub outgoing_tx: Sender<Packet>,
+    pub halt_rx: Receiver<bool>,
+    pub consensus: ConsensusStore,
+    pub clock: Clock,
+    pub identity: [u8; 32],
+    pub rate: f64,
+    pub mean_delay: u64,
+    pub stats: DecoyStats,
+    pub drops: DropSink,
+}
+
+/// A decoy waiting for its SURB reply.
+struct Outstanding {
+    path: Vec<[u8; 32]>,
+    surb_keys: Vec<u8>,
+    sent_at: Instant,
+    deadline: Instant,
+}
+
+pub fn start_decoy_worker(cfg: DecoyConfig) -> JoinHandle<()> {
+    thread::spawn(move || {
+        decoy_worker(cfg)
+    })
+}
+
+fn recipient_id(name: &str) -> [u8; RECIPIENT_ID_SIZE] {
+    let mut id = [0u8; RECIPIENT_ID_SIZE];
+    let len = name.len().min(RECIPIENT_ID_SIZE);
+    id[..len].copy_from_slice(&name.as_bytes()[..len]);
+    id
+}
+
+/// Time until the next decoy, exponentially distributed
+/// so that decoys are sent as a Poisson process.
+fn next_interval<R: Rng>(rng: &mut R, rate: f64) -> Option<Duration> {
+    if rate <= 0.0 {
+        return None
+    }
+    let seconds = Exp::new(rate).ind_sample(rng);
+    Some(Duration::from_millis((seconds * 1000.0) as u64))
+}
+
+fn hop_delay<R: Rng>(rng: &mut R, mean_delay: u64) -> u64 {
+    if mean_delay == 0 {
+        return 0
+    }
+    Exp::new(1.0 / mean_delay as f64).ind_sample(rng) as u64
+}
+
+/// Pick a random node from every layer then a random provider for
+/// the forward path, the first hop being another node than us. The
+/// reply path goes through every layer except our own and ends at us.
+fn select_path<'a, R: Rng>(rng: &mut R, doc: &'a Document, identity: &[u8; 32])
+                           -> Result<(Vec<&'a MixDescriptor>, Vec<&'a MixDescriptor>), DecoyError> {
+    let mut forward = vec![];
+    let mut reply = vec![];
+    for layer in doc.topology.iter() {
+        if forward.is_empty() {
+            let others: Vec<&MixDescriptor> = layer.iter().filter(|x| &x.id() != identity).collect();
+            forward.push(*rng.choose(&others).ok_or(DecoyError::NoPath)?);
+        } else {
+            forward.push(rng.choose(layer).ok_or(DecoyError::NoPath)?);
+        }
+        if !layer.iter().any(|x| &x.id() == identity) {
+            reply.push(rng.choose(layer).ok_or(DecoyError::NoPath)?);
+        }
+    }
+    forward.push(rng.choose(&doc.providers).ok_or(DecoyError::NoPath)?);
+    reply.push(doc.get_node(identity).ok_or(DecoyError::NoPath)?);
+    Ok((forward, reply))
+}
+
+fn path_hop(desc: &MixDescriptor, epoch: u64, commands: Vec<RoutingCommand>) -> Result<PathHop, DecoyError> {
+    let key = match desc.mix_keys.get(&epoch) {
+        Some(x) => public_key_from_bytes(x)?,
+        None => return Err(DecoyError::MissingMixKey(desc.name.clone())),
+    };
+    Ok(PathHop {
+        id: desc.id(),
+        public_key: key,
+        commands: Some(commands),
+    })
+}
+
+/// Build a decoy loop packet and return it along with
+/// its SURB ID and the state needed to match the reply.
+fn new_decoy(cfg: &DecoyConfig, rng: &mut OsRng) -> Result<(Packet, [u8; SURB_ID_SIZE], Outstanding), DecoyError> {
+    let epoch = cfg.clock.now().epoch;
+    let doc = match cfg.consensus.get(epoch) {
+        Some(x) => x,
+        None => return Err(DecoyError::NoDocument),
+    };
+    let (forward, reply) = select_path(rng, &doc, &cfg.identity)?;
+    let mut total_delay = 0;
+
+    let mut surb_id = [0u8; SURB_ID_SIZE];
+    rng.fill_bytes(&mut surb_id);
+    let mut surb_path = vec![];
+    for (i, node) in reply.iter().enumerate() {
+        let commands = if i == reply.len() - 1 {
+            vec![RoutingCommand::Recipient(Recipient { id: recipient_id(&node.name) }),
+                 RoutingCommand::SURBReply(SURBReply { id: surb_id })]
+        } else {
+            let delay = hop_delay(rng, cfg.mean_delay);
+            total_delay += delay;
+            vec![RoutingCommand::Delay(Delay { delay: delay as u32 })]
+        };
+        surb_path.push(path_hop(node, epoch, commands)?);
+    }
+    let (surb, surb_keys) = new_surb(rng, surb_path).map_err(|e| DecoyError::SphinxError(format!("{:?}", e)))?;
+
+    let header_len = constants::SPHINX_PLAINTEXT_HEADER_SIZE;
+    let mut payload = vec![0u8; FORWARD_PAYLOAD_SIZE];
+    payload[0] = constants::SURB_FLAG;
+    payload[header_len..header_len + SURB_SIZE].copy_from_slice(&surb);
+    rng.fill_bytes(&mut payload[header_len + SURB_SIZE..]);
+
+    let mut forward_path = vec![];
+    for (i, node) in forward.iter().enumerate() {
+        let commands = if i == forward.len() - 1 {
+            vec![RoutingCommand::Recipient(Recipient { id: recipient_id(LOOP_SERVICE) })]
+        } else {
+            let delay = hop_delay(rng, cfg.mean_delay);
+            total_delay += delay;
+            vec![RoutingCommand::Delay(Delay { delay: delay as u32 })]
+        };
+        forward_path.push(path_hop(node, epoch, commands)?);
+    }
+    let raw = new_packet(rng, forward_path, payload).map_err(|e| DecoyError::SphinxError(format!("{:?}", e)))?;
+
+    let mut packet = Packet::new(&raw)?;
+    packet.next_hop = Some(NextHop {
+        id: forward[0].id(),
+        mac: [0u8; MAC_SIZE],
+    });
+    let now = Instant::now();
+    let outstanding = Outstanding {
+        path: forward.iter().chain(reply.iter()).map(|x| x.id()).collect(),
+        surb_keys: surb_keys,
+        sent_at: now,
+        deadline: now + Duration::from_millis(total_delay + DECOY_SLACK),
+    };
+    Ok((packet, surb_id, outstanding))
+}
+
+fn on_reply(cfg: &DecoyConfig, outstanding: &mut HashMap<[u8; SURB_ID_SIZE], Outstanding>, packet: Packet) {
+    let decoy = match packet.surb_reply.as_ref().and_then(|x| outstanding.remove(&x.id)) {
+        Some(x) => x,
+        None => {
+            cfg.drops.report(DropReason::DecoyResponse);
+            return
+        },
+    };
+    let valid = match packet.payload {
+        Some(payload) => decrypt_surb_payload(payload, &decoy.surb_keys).is_ok(),
+        None => false,
+    };
+    if !valid {
+        warn!("failed to decrypt decoy reply, counting it as lost");
+        cfg.stats.update(&decoy.path, |x| x.lost += 1);
+        return
+    }
+    let elapsed = decoy.sent_at.elapsed();
+    let latency = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000;
+    debug!("decoy returned after {} ms", latency);
+    cfg.stats.update(&decoy.path, |x| {
+        x.received += 1;
+        x.total_latency += latency;
+    });
+}
+
+fn expire(cfg: &DecoyConfig, outstanding: &mut HashMap<[u8; SURB_ID_SIZE], Outstanding>) {
+    let now = Instant::now();
+    let expired: Vec<[u8; SURB_ID_SIZE]> = outstanding.iter()
+        .filter(|&(_, x)| x.deadline <= now)
+        .map(|(id, _)| *id)
+        .collect();
+    for id in expired {
+        let decoy = outstanding.remove(&id).unwrap();
+        cfg.stats.update(&decoy.path, |x| x.lost += 1);
+    }
+    cfg.stats.prune(now);
+}
+
+fn decoy_worker(cfg: DecoyConfig) {
+    let mut rng = match OsRng::new() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("decoy worker failed to create rng: {}", e);
+            return
+        },
+    };
+    let mut outstanding: HashMap<[u8; SURB_ID_SIZE], Outstanding> = HashMap::new();
+    let mut next_send = next_interval(&mut rng, cfg.rate).map(|x| Instant::now() + x);
+    let mut sel = Select::new();
+    let oper1 = sel.recv(&cfg.decoy_rx);
+    let oper2 = sel.recv(&cfg.halt_rx);
+    loop {
+        let oper = match next_send {
+            Some(at) => {
+                let now = Instant::now();
+                if now >= at {
+                    expire(&cfg, &mut outstanding);
+                    match new_decoy(&cfg, &mut rng) {
+                        Ok((packet, surb_id, decoy)) => {
+                            cfg.stats.update(&decoy.path, |x| x.sent += 1);
+                            outstanding.insert(surb_id, decoy);
+                            if let Err(e) = cfg.outgoing_tx.send(packet) {
+                                warn!("decoy worker failed to send packet: {}", e);
+                                return
+                            }
+                        },
+                        Err(e) => debug!("not sending decoy: {}", e),
+                    }
+                    next_send = next_interval(&mut rng, cfg.rate).map(|x| now + x);
+                    continue
+                }
+                match sel.select_timeout(at - now) {
+                    Ok(x) => x,
+                    Err(_) => continue,
+                }
+            },
+            None => sel.select(),
+        };
+        match oper.index() {
+            i if i == oper1 => {
+                match oper.recv(&cfg.decoy_rx) {
+                    Ok(packet) => on_reply(&cfg, &mut outstanding, packet),
+                    Err(_) => {
+                        debug!("decoy queue closed, halting.");
+                        return
+                    },
+                }
+            },
+            i if i == oper2 => {
+                // The halt channel is closed to signal shutdown.
+                let _ = oper.recv(&cfg.halt_rx);
+                return
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate ecdh_wrapper;
+
+    use std::collections::HashMap;
+    use self::ecdh_wrapper::PrivateKey;
+    use crossbeam_channel::unbounded;
+    use sphinxcrypto::client::new_packet_from_surb;
+    use sphinxcrypto::server::sphinx_packet_unwrap;
+    use pki::{Document, MixDescriptor};
+    use super::*;
+
+    fn test_config() -> DecoyConfig {
+        let (_, decoy_rx) = unbounded();
+        let (outgoing_tx, _) = unbounded();
+        let (_, halt_rx) = unbounded();
+        DecoyConfig {
+            decoy_rx: decoy_rx,
+            outgoing_tx: outgoing_tx,
+            halt_rx: halt_rx,
+            consensus: ConsensusStore::new(),
+            clock: Clock::new_katzenpost(),
+            identity: [1u8; 32],
+            rate: 1.0,
+            mean_delay: 0,
+            stats: DecoyStats::new(),
+            drops: DropSink::new(),
+        }
+    }
+
+    fn node_path(seeds: &[u8]) -> Vec<[u8; 32]> {
+        seeds.iter().map(|x| [*x; 32]).collect()
+    }
+
+    fn outstanding(path: &[u8], surb_keys: Vec<u8>, deadline: Instant) -> Outstanding {
+        Outstanding {
+            path: node_path(path),
+            surb_keys: surb_keys,
+            sent_at: Instant::now(),
+            deadline: deadline,
+        }
+    }
+
+    fn descriptor(name: &str, seed: u8, is_provider: bool) -> MixDescriptor {
+        MixDescriptor {
+            name: name.to_string(),
+            identity_key: [seed; 32],
+            link_key: [seed; 32],
+            addresses: vec![],
+            is_provider: is_provider,
+            layer: 0,
+            mix_keys: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn decoy_select_path_test() {
+        let doc = Document {
+            epoch: 1,
+            topology: vec![
+                vec![descriptor("a", 1, false), descriptor("b", 2, false)],
+                vec![descriptor("c", 3, false)],
+                vec![descriptor("d", 4, false), descriptor("e", 5, false)],
+            ],
+            providers: vec![descriptor("p", 6, true)],
+        };
+        let mut rng = OsRng::new().unwrap();
+        let (forward, reply) = select_path(&mut rng, &doc, &[3; 32]).unwrap();
+        assert_eq!(forward.len(), 4);
+        assert_eq!(forward[1].name, "c");
+        assert_eq!(forward[3].name, "p");
+        assert_eq!(reply.len(), 3);
+        assert!(reply[..2].iter().all(|x| x.name != "c"));
+        assert_eq!(reply[2].name, "c");
+
+        // We are never our own first hop.
+        let doc = Document {
+            epoch: 1,
+            topology: vec![
+                vec![descriptor("a", 1, false), descriptor("b", 2, false)],
+                vec![descriptor("c", 3, false)],
+            ],
+            providers: vec![descriptor("p", 6, true)],
+        };
+        for _ in 0..32 {
+            let (forward, _) = select_path(&mut rng, &doc, &[1; 32]).unwrap();
+            assert_eq!(forward[0].name, "b");
+        }
+        let doc = Document {
+            epoch: 1,
+            topology: vec![vec![descriptor("a", 1, false)]],
+            providers: vec![descriptor("p", 6, true)],
+        };
+        match select_path(&mut rng, &doc, &[1; 32]) {
+            Err(DecoyError::NoPath) => {},
+            _ => panic!("path starting at ourself selected"),
+        }
+    }
+
+    #[test]
+    fn decoy_on_reply_test() {
+        let cfg = test_config();
+        let mut rng = OsRng::new().unwrap();
+        let key = PrivateKey::generate(&mut rng).unwrap();
+        let surb_id = [7u8; SURB_ID_SIZE];
+        let hop = PathHop {
+            id: cfg.identity,
+            public_key: key.public_key(),
+            commands: Some(vec![RoutingCommand::Recipient(Recipient { id: recipient_id("mix") }),
+                                RoutingCommand::SURBReply(SURBReply { id: surb_id })]),
+        };
+        let (surb, surb_keys) = new_surb(&mut rng, vec![hop]).unwrap();
+        let (raw, _) = new_packet_from_surb(surb, vec![0u8; FORWARD_PAYLOAD_SIZE]).unwrap();
+        let mut reply = Packet::new(&raw).unwrap();
+        let (payload, _, cmds, err) = sphinx_packet_unwrap(&key, &mut reply.raw);
+        assert!(err.is_none());
+        reply.set_payload(payload);
+        reply.set_commands(cmds.unwrap());
+
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let mut pending = HashMap::new();
+        pending.insert(surb_id, outstanding(&[1, 6], surb_keys.clone(), deadline));
+        on_reply(&cfg, &mut pending, reply);
+        assert!(pending.is_empty());
+        let path = node_path(&[1, 6]);
+        assert_eq!(cfg.stats.snapshot()[&path].received, 1);
+
+        // A reply nobody waits for is dropped.
+        let mut unknown = Packet::default();
+        unknown.surb_reply = Some(SURBReply { id: [8u8; SURB_ID_SIZE] });
+        on_reply(&cfg, &mut pending, unknown);
+        assert_eq!(cfg.drops.count(DropReason::DecoyResponse), 1);
+
+        // A reply which fails to decrypt counts as lost.
+        pending.insert(surb_id, outstanding(&[1, 6], surb_keys, deadline));
+        let mut garbled = Packet::default();
+        garbled.surb_reply = Some(SURBReply { id: surb_id });
+        garbled.payload = Some(vec![1u8; FORWARD_PAYLOAD_SIZE]);
+        on_reply(&cfg, &mut pending, garbled);
+        assert!(pending.is_empty());
+        assert_eq!(cfg.stats.snapshot()[&path].received, 1);
+        assert_eq!(cfg.stats.snapshot()[&path].lost, 1);
+    }
+
+    #[test]
+    fn decoy_expire_test() {
+        let cfg = test_config();
+        let now = Instant::now();
+        let mut pending = HashMap::new();
+        pending.insert([1u8; SURB_ID_SIZE], outstanding(&[1], vec![], now));
+        pending.insert([2u8; SURB_ID_SIZE], outstanding(&[2], vec![], now + Duration::from_secs(60)));
+        expire(&cfg, &mut pending);
+        assert_eq!(pending.len(), 1);
+        assert!(pending.contains_key(&[2u8; SURB_ID_SIZE]));
+        let stats = cfg.stats.snapshot();
+        assert_eq!(stats[&node_path(&[1])].lost, 1);
+        assert!(!stats.contains_key(&node_path(&[2])));
+    }
+
+    #[test]
+    fn decoy_stats_test() {
+        let stats = DecoyStats::new();
+        let path = node_path(&[1, 3]);
+        stats.update(&path, |x| x.sent += 1);
+        stats.update(&path, |x| {
+            x.received += 1;
+            x.total_latency += 40;
+        });
+        assert_eq!(stats.snapshot()[&path].mean_latency(), Some(40));
+
+        // The least recently updated path makes room for a new one.
+        for i in 0..MAX_PATHS {
+            stats.update(&[[0u8; 32], [(i / 256) as u8; 32], [(i % 256) as u8; 32]], |x| x.sent += 1);
+        }
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.len(), MAX_PATHS);
+        assert!(!snapshot.contains_key(&path));
+
+        // Paths not updated for an epoch are forgotten.
+        stats.update(&path, |x| x.sent += 1);
+        stats.prune(Instant::now() + Duration::from_millis(PATH_STATS_TTL + 1));
+        assert!(stats.snapshot().is_empty());
+    }
+}
@@ -0,0 +1,117 @@
+// descriptor.rs - Mix descriptor generation.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+extern crate ecdh_wrapper;
+extern crate ed25519_dalek;
+extern crate serde_cbor;
+extern crate sphinx_replay_cache;
+
+use std::collections::HashMap;
+
+use ecdh_wrapper::PublicKey;
+use ed25519_dalek::Keypair;
+use sphinx_replay_cache::MixKeys;
+
+use super::pki::{MixDescriptor, SignedDocument, public_key_to_bytes};
+use super::errors::PkiError;
+use super::constants;
+
+
+/// DescriptorBuilder assembles and signs the descriptor
+/// this server uploads to the PKI.
+pub struct DescriptorBuilder {
+    pub name: String,
+    pub addresses: Vec<String>,
+    pub is_provider: bool,
+    pub link_key: PublicKey,
+    pub identity_key: Keypair,
+    pub mix_keys: MixKeys,
+}
+
+impl DescriptorBuilder {
+    /// Build the descriptor for the given epoch, advertising the
+    /// mix keys of that epoch and the ones following it.
+    pub fn build(&self, epoch: u64) -> MixDescriptor {
+        let mut mix_keys = HashMap::new();
+        for e in epoch..epoch + constants::NUM_MIX_KEYS as u64 {
+            match self.mix_keys.get_public_key(e) {
+                Some(key) => {
+                    mix_keys.insert(e, public_key_to_bytes(&key));
+                },
+                None => warn!("no mix key for epoch {} to publish", e),
+            }
+        }
+        MixDescriptor {
+            name: self.name.clone(),
+            identity_key: self.identity_key.public.to_bytes(),
+            link_key: public_key_to_bytes(&self.link_key),
+            addresses: self.addresses.clone(),
+            is_provider: self.is_provider,
+            layer: 0, // assigned by the authority
+            mix_keys: mix_keys,
+        }
+    }
+
+    /// Build the descriptor for the given epoch and return
+    /// its signed serialized form ready for upload.
+    pub fn build_signed(&self, epoch: u64) -> Result<Vec<u8>, PkiError> {
+        let descriptor = self.build(epoch);
+        let payload = serde_cbor::to_vec(&descriptor).map_err(|e| PkiError::EncodeError(format!("{}", e)))?;
+        SignedDocument::sign(payload, &self.identity_key).to_bytes()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate epoch;
+    extern crate rand;
+    extern crate tempfile;
+
+    use self::epoch::Clock;
+    use self::rand::os::OsRng;
+    use self::tempfile::TempDir;
+    use ecdh_wrapper::PrivateKey;
+    use identity::generate_identity_key;
+    use super::*;
+
+    #[test]
+    fn descriptor_sign_verify_test() {
+        let dir = TempDir::new().unwrap();
+        let clock = Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let mut mix_keys = MixKeys::new(clock, constants::NUM_MIX_KEYS,
+                                        dir.path().to_str().unwrap().to_string(), 1000).unwrap();
+        mix_keys.generate(epoch).unwrap();
+        let builder = DescriptorBuilder {
+            name: "mix1".to_string(),
+            addresses: vec!["127.0.0.1:29483".to_string()],
+            is_provider: false,
+            link_key: PrivateKey::generate(&mut OsRng::new().unwrap()).unwrap().public_key(),
+            identity_key: generate_identity_key().unwrap(),
+            mix_keys: mix_keys,
+        };
+
+        let signed = SignedDocument::from_bytes(&builder.build_signed(epoch).unwrap()).unwrap();
+        assert!(signed.is_signed_by(&builder.identity_key.public));
+        assert!(!signed.is_signed_by(&generate_identity_key().unwrap().public));
+        let descriptor: MixDescriptor = serde_cbor::from_slice(&signed.payload).unwrap();
+        assert_eq!(descriptor.name, "mix1");
+        assert_eq!(descriptor.identity_key, builder.identity_key.public.to_bytes());
+        assert_eq!(descriptor.link_key, public_key_to_bytes(&builder.link_key));
+        assert!(descriptor.mix_keys.contains_key(&epoch));
+    }
+}
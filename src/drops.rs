@@ -0,0 +1,148 @@
+// drops.rs - Packet drop accounting.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+
+/// Why a packet was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DropReason {
+    /// Waited longer than the slack time for a crypto worker.
+    DwellTime,
+    /// The AQM signaled congestion of the crypto worker queue.
+    Congestion,
+    /// The Sphinx packet could not be unwrapped, or was a replay.
+    UnwrapFailed,
+    /// A provider received a forward packet from a mix.
+    ProviderForward,
+    /// A zero delay forward packet arrived too late to be sent on time.
+    ZeroDelay,
+    /// A decoy traffic response that nothing is waiting for.
+    DecoyResponse,
+    /// A mix received a packet not addressed to another mix.
+    InvalidMixPacket,
+    /// A client sent a packet that is not to be forwarded.
+    ClientPacket,
+    /// A packet for a local user with invalid routing
+    /// commands, recipient or payload.
+    InvalidUserPacket,
+    /// The next hop is missing, or not in the current PKI document.
+    NoRoute,
+    /// The next hop could not be reached.
+    PeerUnreachable,
+    /// A message for a local user could not be spooled.
+    SpoolFailed,
+}
+
+const NUM_DROP_REASONS: usize = 12;
+
+pub const DROP_REASONS: [DropReason; NUM_DROP_REASONS] = [
+    DropReason::DwellTime,
+    DropReason::Congestion,
+    DropReason::UnwrapFailed,
+    DropReason::ProviderForward,
+    DropReason::ZeroDelay,
+    DropReason::DecoyResponse,
+    DropReason::InvalidMixPacket,
+    DropReason::ClientPacket,
+    DropReason::InvalidUserPacket,
+    DropReason::NoRoute,
+    DropReason::PeerUnreachable,
+    DropReason::SpoolFailed,
+];
+
+impl DropReason {
+    /// Returns the name used as the metrics label.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            DropReason::DwellTime => "dwell_time",
+            DropReason::Congestion => "congestion",
+            DropReason::UnwrapFailed => "unwrap_failed",
+            DropReason::ProviderForward => "provider_forward",
+            DropReason::ZeroDelay => "zero_delay",
+            DropReason::DecoyResponse => "decoy_response",
+            DropReason::InvalidMixPacket => "invalid_mix_packet",
+            DropReason::ClientPacket => "client_packet",
+            DropReason::InvalidUserPacket => "invalid_user_packet",
+            DropReason::NoRoute => "no_route",
+            DropReason::PeerUnreachable => "peer_unreachable",
+            DropReason::SpoolFailed => "spool_failed",
+        }
+    }
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Default)]
+struct DropCounts {
+    counts: [AtomicUsize; NUM_DROP_REASONS],
+}
+
+/// DropSink is where every packet drop is reported. Clones
+/// share the same counters.
+#[derive(Clone, Default)]
+pub struct DropSink {
+    state: Arc<DropCounts>,
+}
+
+impl DropSink {
+    pub fn new() -> DropSink {
+        DropSink::default()
+    }
+
+    pub fn report(&self, reason: DropReason) {
+        debug!("Dropping packet: ({})", reason);
+        self.state.counts[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of packets dropped for the given reason.
+    pub fn count(&self, reason: DropReason) -> u64 {
+        self.state.counts[reason as usize].load(Ordering::Relaxed) as u64
+    }
+
+    /// Returns the drop count of every reason.
+    pub fn counts(&self) -> Vec<(DropReason, u64)> {
+        DROP_REASONS.iter().map(|x| (*x, self.count(*x))).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_sink_count_test() {
+        let sink = DropSink::new();
+        let other = sink.clone();
+        sink.report(DropReason::ZeroDelay);
+        other.report(DropReason::ZeroDelay);
+        other.report(DropReason::Congestion);
+        assert_eq!(sink.count(DropReason::ZeroDelay), 2);
+        assert_eq!(sink.count(DropReason::Congestion), 1);
+        assert_eq!(sink.count(DropReason::DwellTime), 0);
+        assert_eq!(sink.counts().len(), DROP_REASONS.len());
+        for (i, reason) in DROP_REASONS.iter().enumerate() {
+            assert_eq!(*reason as usize, i);
+        }
+    }
+}
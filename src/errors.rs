@@ -22,12 +22,32 @@ use std::fmt;
 use toml;
 
 use ecdh_wrapper::errors::KeyError;
+use mix_link::errors::HandshakeError;
+
+use super::constants;
 
 
 #[derive(Debug)]
 pub enum ConfigError {
     IoError(std::io::Error),
     TomlError(toml::de::Error),
+    SerializeError(toml::ser::Error),
+    InvalidAddress(String, String),
+    ZeroWorkers(String),
+    NoPki,
+    AmbiguousPki,
+    InvalidPublicKey(String),
+    PlaceholderKey(String),
+    MissingDataDir(String),
+    UnknownLogLevel(String, String),
+    EmptyField(String),
+    InvalidSampleRate(f64),
+    InvalidDecoyRate(f64),
+    ZeroConcurrency(String),
+    ReservedName(String, String),
+    DuplicateName(String, String),
+    UnsupportedEpochDuration(u64),
+    Invalid(Vec<ConfigError>),
 }
 
 impl fmt::Display for ConfigError {
@@ -36,6 +56,27 @@ impl fmt::Display for ConfigError {
         match self {
             IoError(x) => x.fmt(f),
             TomlError(x) => x.fmt(f),
+            SerializeError(x) => x.fmt(f),
+            InvalidAddress(field, address) => write!(f, "{}: invalid address \"{}\"", field, address),
+            ZeroWorkers(field) => write!(f, "{}: must be at least 1", field),
+            NoPki => write!(f, "pki: one of pki.nonvoting or pki.voting must be set"),
+            AmbiguousPki => write!(f, "pki: only one of pki.nonvoting or pki.voting may be set"),
+            InvalidPublicKey(field) => write!(f, "{}: undecodable public key", field),
+            PlaceholderKey(field) => write!(f, "{}: the all zero placeholder key must be replaced", field),
+            MissingDataDir(dir) => write!(f, "server.data_dir: directory \"{}\" does not exist", dir),
+            UnknownLogLevel(field, level) => write!(f, "{}: unknown log level \"{}\"", field, level),
+            EmptyField(field) => write!(f, "{}: must not be empty", field),
+            InvalidSampleRate(rate) => write!(f, "tracing.sample_rate: {} is not in (0, 1]", rate),
+            InvalidDecoyRate(rate) => write!(f, "decoy.rate: {} is not a finite, non negative rate", rate),
+            ZeroConcurrency(field) => write!(f, "{}: at least one request must be allowed in flight", field),
+            ReservedName(field, name) => write!(f, "{}: \"{}\" is reserved for a built-in service", field, name),
+            DuplicateName(field, name) => write!(f, "{}: \"{}\" is already used by another plugin", field, name),
+            UnsupportedEpochDuration(duration) => write!(f, "pki.voting.epoch_duration: {} is not the katzenpost epoch of {} seconds",
+                                                         duration, constants::EPOCH_DURATION),
+            Invalid(errors) => {
+                let messages: Vec<String> = errors.iter().map(|x| x.to_string()).collect();
+                write!(f, "invalid configuration: {}", messages.join("; "))
+            },
         }
     }
 }
@@ -50,6 +91,8 @@ impl Error for ConfigError {
         match self {
             IoError(x) => x.cause(),
             TomlError(x) => x.cause(),
+            SerializeError(x) => x.cause(),
+            _ => None,
         }
     }
 }
@@ -66,6 +109,12 @@ impl From<toml::de::Error> for ConfigError {
     }
 }
 
+impl From<toml::ser::Error> for ConfigError {
+    fn from(error: toml::ser::Error) -> Self {
+        ConfigError::SerializeError(error)
+    }
+}
+
 #[derive(Debug)]
 pub enum MixKeyError {
     CreateCacheFailed,
@@ -119,6 +168,7 @@ pub enum UnwrapPacketError {
     NoKey,
     CacheFail,
     Replay,
+    DecryptFail,
 }
 
 impl fmt::Display for UnwrapPacketError {
@@ -128,6 +178,7 @@ impl fmt::Display for UnwrapPacketError {
             NoKey => write!(f, "no mix key found"),
             CacheFail => write!(f, "cache failure"),
             Replay => write!(f, "sphinx packet replay detected"),
+            DecryptFail => write!(f, "no mix key could decrypt the packet"),
         }
     }
 }
@@ -143,6 +194,7 @@ impl Error for UnwrapPacketError {
             NoKey => None,
             CacheFail => None,
             Replay => None,
+            DecryptFail => None,
         }
     }
 }
@@ -150,6 +202,10 @@ impl Error for UnwrapPacketError {
 #[derive(Debug)]
 pub enum PacketError {
     WrongSize,
+    InvalidPayload,
+    DuplicateCommand,
+    ReplyTooLarge(usize),
+    SphinxError(String),
 }
 
 impl fmt::Display for PacketError {
@@ -157,6 +213,10 @@ impl fmt::Display for PacketError {
         use self::PacketError::*;
         match self {
             WrongSize => write!(f, ""),
+            InvalidPayload => write!(f, "invalid forward payload"),
+            DuplicateCommand => write!(f, "repeated routing command"),
+            ReplyTooLarge(x) => write!(f, "reply of {} bytes does not fit in a SURB reply", x),
+            SphinxError(x) => write!(f, "failed to create SURB reply: {}", x),
         }
     }
 }
@@ -170,6 +230,403 @@ impl Error for PacketError {
         use self::PacketError::*;
         match self {
             WrongSize => None,
+            InvalidPayload => None,
+            DuplicateCommand => None,
+            ReplyTooLarge(_) => None,
+            SphinxError(_) => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum OutgoingError {
+    NoDocument,
+    UnknownPeer,
+    ConnectFailed,
+    KeyError(KeyError),
+    HandshakeError(HandshakeError),
+}
+
+impl fmt::Display for OutgoingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::OutgoingError::*;
+        match self {
+            NoDocument => write!(f, "no PKI document for the current epoch"),
+            UnknownPeer => write!(f, "peer not found in PKI document"),
+            ConnectFailed => write!(f, "failed to connect to any peer address"),
+            KeyError(x) => x.fmt(f),
+            HandshakeError(x) => x.fmt(f),
+        }
+    }
+}
+
+impl Error for OutgoingError {
+    fn description(&self) -> &str {
+        "I'm an OutgoingError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::OutgoingError::*;
+        match self {
+            NoDocument => None,
+            UnknownPeer => None,
+            ConnectFailed => None,
+            KeyError(x) => x.cause(),
+            HandshakeError(x) => x.cause(),
+        }
+    }
+}
+
+impl From<KeyError> for OutgoingError {
+    fn from(error: KeyError) -> Self {
+        OutgoingError::KeyError(error)
+    }
+}
+
+impl From<HandshakeError> for OutgoingError {
+    fn from(error: HandshakeError) -> Self {
+        OutgoingError::HandshakeError(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum SpoolError {
+    InvalidRecipient,
+    CorruptMessage,
+    CorruptClientKey,
+    DbError(String),
+}
+
+impl fmt::Display for SpoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::SpoolError::*;
+        match self {
+            InvalidRecipient => write!(f, "invalid recipient"),
+            CorruptMessage => write!(f, "corrupt spooled message"),
+            CorruptClientKey => write!(f, "corrupt client link key"),
+            DbError(x) => write!(f, "spool database error: {}", x),
+        }
+    }
+}
+
+impl Error for SpoolError {
+    fn description(&self) -> &str {
+        "I'm a SpoolError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        None
+    }
+}
+
+#[derive(Debug)]
+pub enum RetrieveMessageError {
+    OutOfSequence,
+    SpoolError(SpoolError),
+}
+
+impl fmt::Display for RetrieveMessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::RetrieveMessageError::*;
+        match self {
+            OutOfSequence => write!(f, "RetrieveMessage out of sequence"),
+            SpoolError(x) => x.fmt(f),
+        }
+    }
+}
+
+impl Error for RetrieveMessageError {
+    fn description(&self) -> &str {
+        "I'm a RetrieveMessageError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::RetrieveMessageError::*;
+        match self {
+            OutOfSequence => None,
+            SpoolError(x) => x.cause(),
+        }
+    }
+}
+
+impl From<SpoolError> for RetrieveMessageError {
+    fn from(error: SpoolError) -> Self {
+        RetrieveMessageError::SpoolError(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum PkiError {
+    NotConfigured,
+    ConnectFailed,
+    UnexpectedResponse,
+    NotAvailable(u8),
+    DescriptorRejected(u8),
+    InvalidSignature,
+    InsufficientSignatures(usize, usize),
+    InvalidDocument,
+    DecodeError(String),
+    EncodeError(String),
+    KeyError(KeyError),
+    HandshakeError(HandshakeError),
+}
+
+impl fmt::Display for PkiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::PkiError::*;
+        match self {
+            NotConfigured => write!(f, "no PKI authority configured"),
+            ConnectFailed => write!(f, "failed to connect to the PKI authority"),
+            UnexpectedResponse => write!(f, "unexpected response from the PKI authority"),
+            NotAvailable(x) => write!(f, "consensus not available, error code {}", x),
+            DescriptorRejected(x) => write!(f, "descriptor rejected, error code {}", x),
+            InvalidSignature => write!(f, "invalid consensus signature"),
+            InsufficientSignatures(x, y) => write!(f, "consensus has {} authority signatures, {} required", x, y),
+            InvalidDocument => write!(f, "invalid consensus document"),
+            DecodeError(x) => write!(f, "failed to decode: {}", x),
+            EncodeError(x) => write!(f, "failed to encode: {}", x),
+            KeyError(x) => x.fmt(f),
+            HandshakeError(x) => x.fmt(f),
+        }
+    }
+}
+
+impl Error for PkiError {
+    fn description(&self) -> &str {
+        "I'm a PkiError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::PkiError::*;
+        match self {
+            KeyError(x) => x.cause(),
+            HandshakeError(x) => x.cause(),
+            _ => None,
+        }
+    }
+}
+
+impl From<KeyError> for PkiError {
+    fn from(error: KeyError) -> Self {
+        PkiError::KeyError(error)
+    }
+}
+
+impl From<HandshakeError> for PkiError {
+    fn from(error: HandshakeError) -> Self {
+        PkiError::HandshakeError(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum IdentityKeyError {
+    InvalidPem,
+    DecodeError(String),
+    IoError(IoError),
+}
+
+impl fmt::Display for IdentityKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::IdentityKeyError::*;
+        match self {
+            InvalidPem => write!(f, "invalid identity key PEM file"),
+            DecodeError(x) => write!(f, "failed to decode identity key: {}", x),
+            IoError(x) => x.fmt(f),
+        }
+    }
+}
+
+impl Error for IdentityKeyError {
+    fn description(&self) -> &str {
+        "I'm an IdentityKeyError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::IdentityKeyError::*;
+        match self {
+            InvalidPem => None,
+            DecodeError(_) => None,
+            IoError(x) => x.cause(),
+        }
+    }
+}
+
+impl From<IoError> for IdentityKeyError {
+    fn from(error: IoError) -> Self {
+        IdentityKeyError::IoError(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum ServerError {
+    LinkKeyError(String),
+    IdentityKeyError(IdentityKeyError),
+    MixKeyError(String),
+    PkiError(PkiError),
+    SpoolError(SpoolError),
+    LoggingError(String),
+    ListenError(String, std::io::Error),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ServerError::*;
+        match self {
+            LinkKeyError(x) => write!(f, "failed to load link keys: {}", x),
+            IdentityKeyError(x) => write!(f, "failed to load identity key: {}", x),
+            MixKeyError(x) => write!(f, "failed to load or generate mix keys: {}", x),
+            PkiError(x) => write!(f, "failed to create PKI client: {}", x),
+            SpoolError(x) => write!(f, "failed to open user spool: {}", x),
+            LoggingError(x) => write!(f, "failed to initialize logging: {}", x),
+            ListenError(address, x) => write!(f, "failed to listen on {}: {}", address, x),
+        }
+    }
+}
+
+impl Error for ServerError {
+    fn description(&self) -> &str {
+        "I'm a ServerError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::ServerError::*;
+        match self {
+            LinkKeyError(_) => None,
+            IdentityKeyError(x) => x.cause(),
+            MixKeyError(_) => None,
+            PkiError(x) => x.cause(),
+            SpoolError(x) => x.cause(),
+            LoggingError(_) => None,
+            ListenError(_, x) => x.cause(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DecoyError {
+    NoDocument,
+    NoPath,
+    MissingMixKey(String),
+    KeyError(KeyError),
+    SphinxError(String),
+    PacketError(PacketError),
+}
+
+impl fmt::Display for DecoyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::DecoyError::*;
+        match self {
+            NoDocument => write!(f, "no PKI document for the current epoch"),
+            NoPath => write!(f, "no usable path in the PKI document"),
+            MissingMixKey(x) => write!(f, "no mix key for the current epoch in descriptor of {}", x),
+            KeyError(x) => x.fmt(f),
+            SphinxError(x) => write!(f, "failed to create sphinx packet: {}", x),
+            PacketError(x) => x.fmt(f),
+        }
+    }
+}
+
+impl Error for DecoyError {
+    fn description(&self) -> &str {
+        "I'm a DecoyError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::DecoyError::*;
+        match self {
+            KeyError(x) => x.cause(),
+            PacketError(x) => x.cause(),
+            _ => None,
         }
     }
 }
+
+impl From<KeyError> for DecoyError {
+    fn from(error: KeyError) -> Self {
+        DecoyError::KeyError(error)
+    }
+}
+
+impl From<PacketError> for DecoyError {
+    fn from(error: PacketError) -> Self {
+        DecoyError::PacketError(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum KaetzchenError {
+    NoSurb,
+    PluginGone,
+    PacketError(PacketError),
+}
+
+impl fmt::Display for KaetzchenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::KaetzchenError::*;
+        match self {
+            NoSurb => write!(f, "request carries no SURB to reply with"),
+            PluginGone => write!(f, "plugin worker has exited"),
+            PacketError(x) => x.fmt(f),
+        }
+    }
+}
+
+impl Error for KaetzchenError {
+    fn description(&self) -> &str {
+        "I'm a KaetzchenError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::KaetzchenError::*;
+        match self {
+            PacketError(x) => x.cause(),
+            _ => None,
+        }
+    }
+}
+
+impl From<PacketError> for KaetzchenError {
+    fn from(error: PacketError) -> Self {
+        KaetzchenError::PacketError(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum PluginError {
+    IoError(IoError),
+    EncodeError(String),
+    DecodeError(String),
+    FrameTooLarge(usize),
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::PluginError::*;
+        match self {
+            IoError(x) => x.fmt(f),
+            EncodeError(x) => write!(f, "failed to encode plugin request: {}", x),
+            DecodeError(x) => write!(f, "failed to decode plugin response: {}", x),
+            FrameTooLarge(x) => write!(f, "plugin frame of {} bytes is too large", x),
+        }
+    }
+}
+
+impl Error for PluginError {
+    fn description(&self) -> &str {
+        "I'm a PluginError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::PluginError::*;
+        match self {
+            IoError(x) => x.cause(),
+            _ => None,
+        }
+    }
+}
+
+impl From<IoError> for PluginError {
+    fn from(error: IoError) -> Self {
+        PluginError::IoError(error)
+    }
+}
@@ -0,0 +1,114 @@
+// identity.rs - Long term identity key management.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The identity key is a long term Ed25519 signing key, distinct
+//! from the link key, which names this node in the PKI and in
+//! `NextHop` routing commands and signs its descriptors.
+
+extern crate ed25519_dalek;
+extern crate rand;
+extern crate rustc_serialize;
+extern crate sha2;
+
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use ed25519_dalek::{Keypair, PublicKey, SecretKey};
+use rand::Rng;
+use rand::os::OsRng;
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use sha2::Sha512;
+
+use super::errors::IdentityKeyError;
+
+
+const PRIVATE_KEY_FILE: &str = "identity.private.pem";
+const PUBLIC_KEY_FILE: &str = "identity.public.pem";
+const PRIVATE_PEM_TYPE: &str = "ED25519 PRIVATE KEY";
+const PUBLIC_PEM_TYPE: &str = "ED25519 PUBLIC KEY";
+
+fn to_pem(pem_type: &str, raw: &[u8]) -> String {
+    format!("-----BEGIN {}-----\n{}\n-----END {}-----\n", pem_type, raw.to_base64(STANDARD), pem_type)
+}
+
+fn from_pem(pem_type: &str, pem: &str) -> Result<Vec<u8>, IdentityKeyError> {
+    let begin = format!("-----BEGIN {}-----", pem_type);
+    let end = format!("-----END {}-----", pem_type);
+    let lines: Vec<&str> = pem.lines().map(|x| x.trim()).filter(|x| !x.is_empty()).collect();
+    if lines.len() < 3 || lines[0] != begin || lines[lines.len() - 1] != end {
+        return Err(IdentityKeyError::InvalidPem)
+    }
+    let encoded: String = lines[1..lines.len() - 1].concat();
+    encoded.from_base64().map_err(|e| IdentityKeyError::DecodeError(format!("{}", e)))
+}
+
+fn keypair_from_secret(raw: &[u8]) -> Result<Keypair, IdentityKeyError> {
+    let secret = SecretKey::from_bytes(raw).map_err(|e| IdentityKeyError::DecodeError(format!("{:?}", e)))?;
+    let public = PublicKey::from_secret::<Sha512>(&secret);
+    Ok(Keypair {
+        secret: secret,
+        public: public,
+    })
+}
+
+/// Generate a new identity key.
+pub fn generate_identity_key() -> Result<Keypair, IdentityKeyError> {
+    let mut rng = OsRng::new()?;
+    let mut raw = [0u8; 32];
+    rng.fill_bytes(&mut raw);
+    keypair_from_secret(&raw)
+}
+
+/// Load the identity key from the data directory, generating
+/// and saving a new one on first start.
+pub fn load_or_generate_identity_key(data_dir: &str) -> Result<Keypair, IdentityKeyError> {
+    let priv_path = Path::new(data_dir).join(PRIVATE_KEY_FILE);
+    let pub_path = Path::new(data_dir).join(PUBLIC_KEY_FILE);
+    if priv_path.exists() {
+        let mut contents = String::new();
+        File::open(&priv_path)?.read_to_string(&mut contents)?;
+        return keypair_from_secret(&from_pem(PRIVATE_PEM_TYPE, &contents)?)
+    }
+
+    info!("generating new identity key");
+    let keypair = generate_identity_key()?;
+    let mut priv_file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(&priv_path)?;
+    priv_file.write_all(to_pem(PRIVATE_PEM_TYPE, keypair.secret.as_bytes()).as_bytes())?;
+    let mut pub_file = File::create(&pub_path)?;
+    pub_file.write_all(to_pem(PUBLIC_PEM_TYPE, keypair.public.as_bytes()).as_bytes())?;
+    Ok(keypair)
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use super::*;
+
+    #[test]
+    fn identity_key_persistence_test() {
+        let dir = TempDir::new().unwrap();
+        let data_dir = dir.path().to_str().unwrap();
+        let generated = load_or_generate_identity_key(data_dir).unwrap();
+        let loaded = load_or_generate_identity_key(data_dir).unwrap();
+        assert_eq!(generated.public.to_bytes(), loaded.public.to_bytes());
+        assert_eq!(generated.secret.to_bytes(), loaded.secret.to_bytes());
+    }
+}
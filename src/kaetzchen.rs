@@ -0,0 +1,319 @@
+// kaetzchen.rs - Provider side services.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Kaetzchen are services hosted by a provider and addressed by
+//! recipient name. Requests arrive as to-user packets and responses
+//! are sent back using the SURB carried in the request payload.
+
+extern crate crossbeam_channel;
+
+use std::thread;
+use std::thread::JoinHandle;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crossbeam_channel::{Receiver, Sender, Select};
+use super::packet::{Packet, new_surb_reply};
+use super::provider::parse_forward_payload;
+use super::spool::{normalize_recipient, normalize_recipient_id};
+use super::config::Shutdown;
+use super::errors::KaetzchenError;
+
+
+/// Recipient name of the built-in echo service.
+pub const ECHO_SERVICE: &str = "echo";
+
+/// A service reachable as a recipient on this provider.
+pub trait Kaetzchen: Send + Sync {
+    /// Handle a request, returning the response to send
+    /// back through the request's SURB if there is one.
+    fn on_request(&self, request: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// EchoService replies with the request it was sent.
+pub struct EchoService;
+
+impl Kaetzchen for EchoService {
+    fn on_request(&self, request: &[u8]) -> Option<Vec<u8>> {
+        Some(request.to_vec())
+    }
+}
+
+/// A request for an external plugin along with the
+/// SURB its response is to be sent back with.
+pub struct ServiceRequest {
+    pub payload: Vec<u8>,
+    pub surb: Option<Vec<u8>>,
+}
+
+#[derive(Clone)]
+pub enum Service {
+    Builtin(Arc<Kaetzchen>),
+    /// An external plugin, requests are handed to its worker
+    /// which sends the responses itself.
+    Plugin(Sender<ServiceRequest>),
+}
+
+/// KaetzchenRegistry maps normalized recipient names to services.
+#[derive(Clone, Default)]
+pub struct KaetzchenRegistry {
+    services: Arc<RwLock<HashMap<Vec<u8>, Service>>>,
+}
+
+impl KaetzchenRegistry {
+    pub fn new() -> KaetzchenRegistry {
+        KaetzchenRegistry::default()
+    }
+
+    fn insert(&self, name: &str, service: Service) {
+        let id = match normalize_recipient_id(name.as_bytes()) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("not registering kaetzchen \"{}\": {}", name, e);
+                return
+            },
+        };
+        self.services.write().unwrap().insert(id, service);
+    }
+
+    /// Register a service, replacing any previously
+    /// registered under the same name.
+    pub fn register(&self, name: &str, service: Arc<Kaetzchen>) {
+        self.insert(name, Service::Builtin(service));
+    }
+
+    /// Register an external plugin served by the worker
+    /// reading from the other end of `plugin_tx`.
+    pub fn register_plugin(&self, name: &str, plugin_tx: Sender<ServiceRequest>) {
+        self.insert(name, Service::Plugin(plugin_tx));
+    }
+
+    pub fn get(&self, id: &[u8]) -> Option<Service> {
+        self.services.read().unwrap().get(id).cloned()
+    }
+
+    pub fn contains(&self, id: &[u8]) -> bool {
+        self.services.read().unwrap().contains_key(id)
+    }
+}
+
+pub struct KaetzchenConfig {
+    pub kaetzchen_rx: Receiver<Packet>,
+    pub outgoing_tx: Sender<Packet>,
+    pub halt_rx: Receiver<bool>,
+    pub registry: KaetzchenRegistry,
+    pub shutdown: Shutdown,
+}
+
+pub fn start_kaetzchen_worker(cfg: KaetzchenConfig) -> JoinHandle<()> {
+    thread::spawn(move || {
+        kaetzchen_worker(cfg)
+    })
+}
+
+fn on_request(registry: &KaetzchenRegistry, packet: &Packet) -> Result<Option<Packet>, KaetzchenError> {
+    let service = match packet.recipient.as_ref()
+        .and_then(|x| normalize_recipient(x).ok())
+        .and_then(|x| registry.get(&x)) {
+            Some(x) => x,
+            None => {
+                debug!("Dropping kaetzchen request for unknown service.");
+                return Ok(None)
+            },
+        };
+    let payload = match packet.payload {
+        Some(ref x) => x,
+        None => return Ok(None),
+    };
+    let (request, surb) = parse_forward_payload(payload)?;
+    let service = match service {
+        Service::Builtin(x) => x,
+        Service::Plugin(plugin_tx) => {
+            let request = ServiceRequest {
+                payload: request,
+                surb: surb,
+            };
+            if plugin_tx.send(request).is_err() {
+                return Err(KaetzchenError::PluginGone)
+            }
+            return Ok(None)
+        },
+    };
+    let response = match service.on_request(&request) {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    match surb {
+        Some(surb) => Ok(Some(new_surb_reply(surb, &response)?)),
+        None => Err(KaetzchenError::NoSurb),
+    }
+}
+
+/// Returns false if the outgoing dispatcher has gone away.
+fn handle_packet(cfg: &KaetzchenConfig, packet: Packet) -> bool {
+    match on_request(&cfg.registry, &packet) {
+        Ok(Some(reply)) => {
+            if let Err(e) = cfg.outgoing_tx.send(reply) {
+                warn!("kaetzchen worker failed to send reply: {}", e);
+                return false
+            }
+        },
+        Ok(None) => {},
+        Err(e) => debug!("failed to handle kaetzchen request: {}", e),
+    }
+    true
+}
+
+fn kaetzchen_worker(cfg: KaetzchenConfig) {
+    let mut sel = Select::new();
+    let oper1 = sel.recv(&cfg.kaetzchen_rx);
+    let oper2 = sel.recv(&cfg.halt_rx);
+    loop {
+        let oper = sel.select();
+        match oper.index() {
+            i if i == oper1 => {
+                let packet = match oper.recv(&cfg.kaetzchen_rx) {
+                    Ok(x) => x,
+                    Err(_) => {
+                        debug!("kaetzchen queue closed, halting.");
+                        return
+                    },
+                };
+                if !handle_packet(&cfg, packet) {
+                    return
+                }
+            },
+            i if i == oper2 => {
+                // The halt channel is closed to signal shutdown.
+                let _ = oper.recv(&cfg.halt_rx);
+                // Serve the requests still queued by the crypto workers.
+                cfg.shutdown.drain("kaetzchen worker", &cfg.kaetzchen_rx, |packet| handle_packet(&cfg, packet));
+                return
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate ecdh_wrapper;
+    extern crate rand;
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use self::ecdh_wrapper::PrivateKey;
+    use self::rand::os::OsRng;
+    use crossbeam_channel::unbounded;
+    use sphinxcrypto::client::{new_surb, decrypt_surb_payload, PathHop};
+    use sphinxcrypto::commands::{RoutingCommand, Recipient, SURBReply};
+    use sphinxcrypto::constants::{RECIPIENT_ID_SIZE, SURB_ID_SIZE, SURB_SIZE};
+    use sphinxcrypto::server::sphinx_packet_unwrap;
+    use constants;
+    use super::*;
+
+    /// Counts the requests it was sent, responding with nothing.
+    struct CountingService(AtomicUsize);
+
+    impl Kaetzchen for CountingService {
+        fn on_request(&self, _request: &[u8]) -> Option<Vec<u8>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Some(vec![])
+        }
+    }
+
+    fn request(name: &str, surb: Option<Vec<u8>>, body: &[u8]) -> Packet {
+        let header_len = constants::SPHINX_PLAINTEXT_HEADER_SIZE;
+        let mut payload = vec![0u8; header_len + SURB_SIZE];
+        if let Some(surb) = surb {
+            payload[0] = constants::SURB_FLAG;
+            payload[header_len..].copy_from_slice(&surb);
+        }
+        payload.extend_from_slice(body);
+        let mut id = [0u8; RECIPIENT_ID_SIZE];
+        id[..name.len()].copy_from_slice(name.as_bytes());
+        let mut packet = Packet::default();
+        packet.recipient = Some(Recipient { id: id });
+        packet.payload = Some(payload);
+        packet
+    }
+
+    #[test]
+    fn kaetzchen_registry_test() {
+        let registry = KaetzchenRegistry::new();
+        registry.register("Echo", Arc::new(EchoService));
+        assert!(registry.contains(b"echo"));
+        assert!(!registry.contains(b"ping"));
+        match registry.get(b"echo") {
+            Some(Service::Builtin(service)) => {
+                assert_eq!(service.on_request(b"hello"), Some(b"hello".to_vec()));
+            },
+            _ => panic!("echo service not found"),
+        }
+    }
+
+    #[test]
+    fn kaetzchen_echo_test() {
+        let (_, kaetzchen_rx) = unbounded();
+        let (outgoing_tx, outgoing_rx) = unbounded();
+        let (_, halt_rx) = unbounded();
+        let cfg = KaetzchenConfig {
+            kaetzchen_rx: kaetzchen_rx,
+            outgoing_tx: outgoing_tx,
+            halt_rx: halt_rx,
+            registry: KaetzchenRegistry::new(),
+            shutdown: Shutdown::default(),
+        };
+        cfg.registry.register(ECHO_SERVICE, Arc::new(EchoService));
+
+        let mut rng = OsRng::new().unwrap();
+        let key = PrivateKey::generate(&mut rng).unwrap();
+        let hop = PathHop {
+            id: [4u8; 32],
+            public_key: key.public_key(),
+            commands: Some(vec![RoutingCommand::Recipient(Recipient { id: [6u8; RECIPIENT_ID_SIZE] }),
+                                RoutingCommand::SURBReply(SURBReply { id: [5u8; SURB_ID_SIZE] })]),
+        };
+        let (surb, surb_keys) = new_surb(&mut rng, vec![hop]).unwrap();
+        assert!(handle_packet(&cfg, request(ECHO_SERVICE, Some(surb), b"hello")));
+
+        // The response goes back through the SURB's first hop.
+        let mut reply = outgoing_rx.try_recv().unwrap();
+        assert_eq!(reply.next_hop.as_ref().unwrap().id, [4u8; 32]);
+        let (payload, _, _, err) = sphinx_packet_unwrap(&key, &mut reply.raw);
+        assert!(err.is_none());
+        let response = decrypt_surb_payload(payload.unwrap(), &surb_keys).unwrap();
+        assert_eq!(&response[..5], b"hello");
+        assert!(response[5..].iter().all(|x| *x == 0));
+    }
+
+    #[test]
+    fn kaetzchen_no_surb_test() {
+        let registry = KaetzchenRegistry::new();
+        let service = Arc::new(CountingService(AtomicUsize::new(0)));
+        registry.register("count", service.clone());
+        match on_request(&registry, &request("count", None, b"hello")) {
+            Err(KaetzchenError::NoSurb) => {},
+            _ => panic!("response without a SURB not reported"),
+        }
+        assert_eq!(service.0.load(Ordering::SeqCst), 1);
+
+        // Requests for unknown services are ignored.
+        assert!(on_request(&registry, &request("nobody", None, b"hello")).unwrap().is_none());
+        assert_eq!(service.0.load(Ordering::SeqCst), 1);
+    }
+}
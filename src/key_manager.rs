@@ -0,0 +1,171 @@
+// key_manager.rs - Epoch driven mix key rotation.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The key manager keeps mix keys generated for the upcoming epochs
+//! and prunes those of past epochs, along with their replay caches,
+//! once the grace period is over. After either it tells the crypto
+//! workers to refresh their shadow copies of the keys.
+
+extern crate crossbeam_channel;
+extern crate epoch;
+extern crate sphinx_replay_cache;
+
+use std::cmp;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crossbeam_channel::{Receiver, Sender, RecvTimeoutError};
+use epoch::Clock;
+use sphinx_replay_cache::MixKeys;
+
+use super::constants;
+
+
+pub struct KeyManagerConfig {
+    pub mix_keys: MixKeys,
+    pub clock: Clock,
+    pub update_txs: Vec<Sender<bool>>,
+    pub halt_rx: Receiver<bool>,
+}
+
+pub fn start_key_manager(cfg: KeyManagerConfig) -> JoinHandle<()> {
+    thread::spawn(move || {
+        key_manager(cfg)
+    })
+}
+
+/// Tell every crypto worker to re-shadow the mix keys.
+fn notify(cfg: &KeyManagerConfig) {
+    for update_tx in cfg.update_txs.iter() {
+        if let Err(e) = update_tx.send(true) {
+            warn!("failed to send mix key update: {}", e);
+        }
+    }
+}
+
+/// The epochs keys were last generated and pruned for.
+#[derive(Default)]
+struct Rotation {
+    generated_epoch: Option<u64>,
+    pruned_epoch: Option<u64>,
+}
+
+/// Generate and prune the mix keys as due at the given point of
+/// the epoch, and notify the crypto workers if either happened.
+fn rotate(cfg: &mut KeyManagerConfig, rotation: &mut Rotation, epoch: u64, elapsed: u64) -> bool {
+    let mut updated = false;
+
+    // Keys for the coming epochs are generated at each epoch
+    // boundary, failures are retried on the next pass.
+    if rotation.generated_epoch != Some(epoch) {
+        match cfg.mix_keys.generate(epoch) {
+            Ok(_) => {
+                debug!("mix keys generated for epoch {}", epoch);
+                rotation.generated_epoch = Some(epoch);
+                updated = true;
+            },
+            Err(e) => warn!("failed to generate mix keys for epoch {}: {}", epoch, e),
+        }
+    }
+
+    // The previous epoch's key is still used to unwrap
+    // packets until the grace period is over.
+    if elapsed >= constants::GRACE_PERIOD && rotation.pruned_epoch != Some(epoch) {
+        cfg.mix_keys.prune();
+        rotation.pruned_epoch = Some(epoch);
+        updated = true;
+    }
+
+    if updated {
+        notify(cfg);
+    }
+    updated
+}
+
+fn key_manager(mut cfg: KeyManagerConfig) {
+    let mut rotation = Rotation::default();
+    loop {
+        let now = cfg.clock.now();
+        rotate(&mut cfg, &mut rotation, now.epoch, now.elapsed);
+
+        let wait = if rotation.generated_epoch != Some(now.epoch) {
+            cmp::min(now.till, constants::GRACE_PERIOD)
+        } else if now.elapsed < constants::GRACE_PERIOD {
+            constants::GRACE_PERIOD - now.elapsed
+        } else {
+            now.till
+        };
+        match cfg.halt_rx.recv_timeout(Duration::from_secs(cmp::max(wait, 1))) {
+            Err(RecvTimeoutError::Timeout) => continue,
+            _ => return,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use std::collections::HashMap;
+    use self::tempfile::TempDir;
+    use crossbeam_channel::unbounded;
+    use super::*;
+
+    #[test]
+    fn key_manager_rotate_test() {
+        let dir = TempDir::new().unwrap();
+        let clock = Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let mix_keys = MixKeys::new(clock.clone(), constants::NUM_MIX_KEYS,
+                                    dir.path().to_str().unwrap().to_string(), 1000).unwrap();
+        let (update_tx1, update_rx1) = unbounded();
+        let (update_tx2, update_rx2) = unbounded();
+        let (_halt_tx, halt_rx) = unbounded();
+        let mut cfg = KeyManagerConfig {
+            mix_keys: mix_keys,
+            clock: clock,
+            update_txs: vec![update_tx1, update_tx2],
+            halt_rx: halt_rx,
+        };
+        let mut rotation = Rotation::default();
+
+        // Keys are generated at the epoch boundary, once.
+        assert!(rotate(&mut cfg, &mut rotation, epoch, 0));
+        assert_eq!(rotation.generated_epoch, Some(epoch));
+        assert_eq!(rotation.pruned_epoch, None);
+        assert!(!rotate(&mut cfg, &mut rotation, epoch, constants::GRACE_PERIOD - 1));
+
+        // Pruning waits for the grace period to be over.
+        assert!(rotate(&mut cfg, &mut rotation, epoch, constants::GRACE_PERIOD));
+        assert_eq!(rotation.pruned_epoch, Some(epoch));
+        assert!(!rotate(&mut cfg, &mut rotation, epoch, constants::GRACE_PERIOD + 1));
+
+        // The next epoch generates again without pruning.
+        assert!(rotate(&mut cfg, &mut rotation, epoch + 1, 0));
+        assert_eq!(rotation.generated_epoch, Some(epoch + 1));
+        assert_eq!(rotation.pruned_epoch, Some(epoch));
+
+        let mut shadow = HashMap::new();
+        cfg.mix_keys.shadow(&mut shadow);
+        assert!(shadow.contains_key(&(epoch + 1)));
+
+        // Every crypto worker heard of each update.
+        assert_eq!(update_rx1.try_iter().count(), 3);
+        assert_eq!(update_rx2.try_iter().count(), 3);
+    }
+}
@@ -31,12 +31,20 @@ extern crate log4rs;
 extern crate toml;
 extern crate bloom;
 extern crate sled;
+extern crate byteorder;
 
 extern crate epoch;
 extern crate ecdh_wrapper;
 extern crate mix_link;
 extern crate sphinxcrypto;
 extern crate sphinx_replay_cache;
+extern crate ed25519_dalek;
+extern crate sha2;
+extern crate serde_cbor;
+extern crate serde_bytes;
+extern crate rustc_serialize;
+extern crate rand;
+extern crate num_cpus;
 
 pub mod server;
 pub mod config;
@@ -46,3 +54,20 @@ pub mod packet;
 pub mod tcp_listener;
 pub mod wire_worker;
 pub mod crypto_worker;
+pub mod scheduler;
+pub mod pki;
+pub mod outgoing;
+pub mod aqm;
+pub mod spool;
+pub mod provider;
+pub mod pki_worker;
+pub mod descriptor;
+pub mod identity;
+pub mod logging;
+pub mod metrics;
+pub mod drops;
+pub mod decoy;
+pub mod kaetzchen;
+pub mod plugin;
+pub mod trace;
+pub mod key_manager;
@@ -0,0 +1,164 @@
+// logging.rs - Mix server logging setup.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::Mutex;
+
+use log::LevelFilter;
+use log4rs;
+use log4rs::Handle;
+use log4rs::append::Append;
+use log4rs::append::console::{ConsoleAppender, Target};
+use log4rs::append::file::FileAppender;
+use log4rs::append::rolling_file::RollingFileAppender;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
+use log4rs::config::{Appender, Config as Log4rsConfig, Logger, Root};
+use log4rs::encode::pattern::PatternEncoder;
+
+use super::config::Logging;
+use super::errors::ServerError;
+
+
+const APPENDER_NAME: &str = "mix_server";
+const LOG_PATTERN: &str = "{d} {l} {M} - {m}{n}";
+
+/// The installed logger. log4rs can only be installed once per
+/// process, later servers in the same process reconfigure it.
+static HANDLE: Mutex<Option<Handle>> = Mutex::new(None);
+
+fn parse_level(field: &str, level: &str) -> Result<LevelFilter, ServerError> {
+    level.parse::<LevelFilter>()
+        .map_err(|_| ServerError::LoggingError(format!("{}: unknown log level \"{}\"", field, level)))
+}
+
+fn build_appender(cfg: &Logging) -> Result<Box<Append>, ServerError> {
+    let encoder = Box::new(PatternEncoder::new(LOG_PATTERN));
+    if cfg.log_file.is_empty() {
+        return Ok(Box::new(ConsoleAppender::builder()
+                           .encoder(encoder)
+                           .target(Target::Stderr)
+                           .build()))
+    }
+    if cfg.max_file_size == 0 {
+        let appender = FileAppender::builder()
+            .encoder(encoder)
+            .build(&cfg.log_file)
+            .map_err(|e| ServerError::LoggingError(format!("{}: {}", cfg.log_file, e)))?;
+        return Ok(Box::new(appender))
+    }
+
+    // Rotated files are named log_file.1 through log_file.max_files.
+    let roller = FixedWindowRoller::builder()
+        .base(1)
+        .build(&format!("{}.{{}}", cfg.log_file), cfg.max_files)
+        .map_err(|e| ServerError::LoggingError(format!("{}: {}", cfg.log_file, e)))?;
+    let policy = CompoundPolicy::new(Box::new(SizeTrigger::new(cfg.max_file_size)), Box::new(roller));
+    let appender = RollingFileAppender::builder()
+        .encoder(encoder)
+        .build(&cfg.log_file, Box::new(policy))
+        .map_err(|e| ServerError::LoggingError(format!("{}: {}", cfg.log_file, e)))?;
+    Ok(Box::new(appender))
+}
+
+/// Build the log4rs configuration described by the `[logging]`
+/// section, or the one logging nothing if logging is disabled.
+fn build_config(cfg: &Logging) -> Result<Log4rsConfig, ServerError> {
+    if cfg.disable {
+        return Log4rsConfig::builder()
+            .build(Root::builder().build(LevelFilter::Off))
+            .map_err(|e| ServerError::LoggingError(format!("{}", e)))
+    }
+    let root_level = parse_level("logging.level", &cfg.level)?;
+    let mut builder = Log4rsConfig::builder()
+        .appender(Appender::builder().build(APPENDER_NAME, build_appender(cfg)?));
+    for (module, level) in cfg.modules.iter() {
+        let field = format!("logging.modules.\"{}\"", module);
+        builder = builder.logger(Logger::builder().build(module.as_str(), parse_level(&field, level)?));
+    }
+    builder
+        .build(Root::builder().appender(APPENDER_NAME).build(root_level))
+        .map_err(|e| ServerError::LoggingError(format!("{}", e)))
+}
+
+/// Install the global logger described by the `[logging]` section,
+/// or replace its configuration if it was already installed. Nothing
+/// is installed when logging is disabled.
+pub fn init_logger(cfg: &Logging) -> Result<(), ServerError> {
+    let config = build_config(cfg)?;
+    let mut handle = HANDLE.lock().unwrap();
+    if let Some(ref handle) = *handle {
+        handle.set_config(config);
+        return Ok(())
+    }
+    if cfg.disable {
+        return Ok(())
+    }
+    *handle = Some(log4rs::init_config(config).map_err(|e| ServerError::LoggingError(format!("{}", e)))?);
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use std::path::Path;
+    use self::tempfile::TempDir;
+    use super::*;
+
+    #[test]
+    fn logging_config_test() {
+        let mut cfg = Logging::default();
+        cfg.level = "WARN".to_string();
+        cfg.modules.insert("mix_server::trace".to_string(), "DEBUG".to_string());
+        let config = build_config(&cfg).unwrap();
+        assert_eq!(config.root().level(), LevelFilter::Warn);
+        assert_eq!(config.loggers().len(), 1);
+        assert_eq!(config.loggers()[0].name(), "mix_server::trace");
+        assert_eq!(config.loggers()[0].level(), LevelFilter::Debug);
+
+        cfg.modules.insert("sled".to_string(), "LOUD".to_string());
+        assert!(build_config(&cfg).is_err());
+
+        // A disabled logger ignores the rest of the section.
+        cfg.disable = true;
+        let config = build_config(&cfg).unwrap();
+        assert_eq!(config.root().level(), LevelFilter::Off);
+        assert!(config.appenders().is_empty());
+    }
+
+    #[test]
+    fn logging_rolling_file_test() {
+        let dir = TempDir::new().unwrap();
+        let log_file = dir.path().join("mix.log").to_str().unwrap().to_string();
+        let mut cfg = Logging::default();
+        cfg.level = "INFO".to_string();
+        cfg.log_file = log_file.clone();
+        cfg.max_file_size = 256;
+        cfg.max_files = 2;
+        init_logger(&cfg).unwrap();
+        for i in 0..20 {
+            info!("rolling file test line {}", i);
+        }
+        assert!(Path::new(&format!("{}.1", log_file)).exists());
+        assert!(!Path::new(&format!("{}.3", log_file)).exists());
+
+        // Servers started later in the same process reconfigure the logger.
+        cfg.disable = true;
+        init_logger(&cfg).unwrap();
+    }
+}
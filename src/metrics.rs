@@ -0,0 +1,219 @@
+// metrics.rs - Packet pipeline metrics.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Counters and gauges for the packet pipeline, served in the
+//! Prometheus text exposition format.
+
+extern crate crossbeam_channel;
+
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::fmt::Write as FmtWrite;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crossbeam_channel::Receiver;
+
+use super::drops::DropSink;
+
+
+/// Upper bounds in milliseconds of the dwell time histogram buckets.
+pub const DWELL_TIME_BUCKETS: [u64; 8] = [1, 5, 10, 25, 50, 100, 250, 1000];
+
+/// Largest scrape request we bother reading.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; 8],
+    sum: u64,
+    count: u64,
+}
+
+#[derive(Default)]
+struct MetricsState {
+    sessions_accepted: AtomicUsize,
+    handshake_failures: AtomicUsize,
+    crypto_queue_depth: AtomicUsize,
+    commands_received: Mutex<BTreeMap<&'static str, u64>>,
+    unwrap_results: Mutex<BTreeMap<&'static str, u64>>,
+    drops: DropSink,
+    dwell_time: Mutex<Histogram>,
+}
+
+/// Metrics is a cheaply clonable handle on the metrics
+/// shared by all of the server's workers.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    state: Arc<MetricsState>,
+}
+
+fn increment(counters: &Mutex<BTreeMap<&'static str, u64>>, label: &'static str) {
+    *counters.lock().unwrap().entry(label).or_insert(0) += 1;
+}
+
+fn encode_labeled(out: &mut String, name: &str, help: &str, label: &str, counters: &Mutex<BTreeMap<&'static str, u64>>) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (value, count) in counters.lock().unwrap().iter() {
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, value, count);
+    }
+}
+
+fn encode_single(out: &mut String, name: &str, help: &str, kind: &str, value: usize) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn session_accepted(&self) {
+        self.state.sessions_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn handshake_failed(&self) {
+        self.state.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn command_received(&self, command: &'static str) {
+        increment(&self.state.commands_received, command);
+    }
+
+    pub fn set_crypto_queue_depth(&self, depth: usize) {
+        self.state.crypto_queue_depth.store(depth, Ordering::Relaxed);
+    }
+
+    /// Record the time in milliseconds a packet spent
+    /// waiting for a crypto worker.
+    pub fn observe_dwell_time(&self, millis: u64) {
+        let mut histogram = self.state.dwell_time.lock().unwrap();
+        for (i, bound) in DWELL_TIME_BUCKETS.iter().enumerate() {
+            if millis <= *bound {
+                histogram.buckets[i] += 1;
+            }
+        }
+        histogram.sum += millis;
+        histogram.count += 1;
+    }
+
+    pub fn unwrap_result(&self, result: &'static str) {
+        increment(&self.state.unwrap_results, result);
+    }
+
+    /// Returns the sink the workers report dropped packets to.
+    pub fn drops(&self) -> &DropSink {
+        &self.state.drops
+    }
+
+    /// Render all metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let state = &self.state;
+        let mut out = String::new();
+        encode_single(&mut out, "mix_sessions_accepted_total", "Link layer sessions accepted.", "counter",
+                      state.sessions_accepted.load(Ordering::Relaxed));
+        encode_single(&mut out, "mix_handshake_failures_total", "Failed link layer handshakes.", "counter",
+                      state.handshake_failures.load(Ordering::Relaxed));
+        encode_labeled(&mut out, "mix_commands_received_total", "Wire protocol commands received.",
+                       "command", &state.commands_received);
+        encode_single(&mut out, "mix_crypto_queue_depth", "Packets waiting for a crypto worker.", "gauge",
+                      state.crypto_queue_depth.load(Ordering::Relaxed));
+
+        let histogram = state.dwell_time.lock().unwrap();
+        let _ = writeln!(out, "# HELP mix_crypto_queue_dwell_milliseconds Time packets waited for a crypto worker.");
+        let _ = writeln!(out, "# TYPE mix_crypto_queue_dwell_milliseconds histogram");
+        for (i, bound) in DWELL_TIME_BUCKETS.iter().enumerate() {
+            let _ = writeln!(out, "mix_crypto_queue_dwell_milliseconds_bucket{{le=\"{}\"}} {}", bound, histogram.buckets[i]);
+        }
+        let _ = writeln!(out, "mix_crypto_queue_dwell_milliseconds_bucket{{le=\"+Inf\"}} {}", histogram.count);
+        let _ = writeln!(out, "mix_crypto_queue_dwell_milliseconds_sum {}", histogram.sum);
+        let _ = writeln!(out, "mix_crypto_queue_dwell_milliseconds_count {}", histogram.count);
+
+        encode_labeled(&mut out, "mix_unwrap_results_total", "Sphinx unwrap results.",
+                       "result", &state.unwrap_results);
+        let _ = writeln!(out, "# HELP mix_packets_dropped_total Packets dropped by the crypto workers.");
+        let _ = writeln!(out, "# TYPE mix_packets_dropped_total counter");
+        for (reason, count) in state.drops.counts() {
+            let _ = writeln!(out, "mix_packets_dropped_total{{reason=\"{}\"}} {}", reason, count);
+        }
+        out
+    }
+}
+
+pub struct MetricsConfig {
+    pub metrics: Metrics,
+    pub stream_rx: Receiver<TcpStream>,
+}
+
+/// Serve scrapes from the connections accepted by a `TcpStreamFount`,
+/// the worker exits once the fount has been halted.
+pub fn start_metrics_worker(cfg: MetricsConfig) -> JoinHandle<()> {
+    thread::spawn(move || {
+        for stream in cfg.stream_rx.iter() {
+            if let Err(e) = serve(&cfg.metrics, stream) {
+                debug!("failed to serve metrics: {}", e);
+            }
+        }
+    })
+}
+
+fn serve(metrics: &Metrics, mut stream: TcpStream) -> Result<(), ::std::io::Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut request = vec![];
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|x| x == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let body = metrics.encode();
+    let response = format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                           body.len(), body);
+    stream.write_all(response.as_bytes())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use drops::DropReason;
+    use super::*;
+
+    #[test]
+    fn metrics_encode_test() {
+        let metrics = Metrics::new();
+        metrics.session_accepted();
+        metrics.command_received("SendPacket");
+        metrics.command_received("SendPacket");
+        metrics.observe_dwell_time(7);
+        metrics.drops().report(DropReason::DwellTime);
+        let text = metrics.encode();
+        assert!(text.contains("mix_sessions_accepted_total 1\n"));
+        assert!(text.contains("mix_commands_received_total{command=\"SendPacket\"} 2\n"));
+        assert!(text.contains("mix_crypto_queue_dwell_milliseconds_bucket{le=\"5\"} 0\n"));
+        assert!(text.contains("mix_crypto_queue_dwell_milliseconds_bucket{le=\"10\"} 1\n"));
+        assert!(text.contains("mix_packets_dropped_total{reason=\"dwell_time\"} 1\n"));
+        assert!(text.contains("mix_packets_dropped_total{reason=\"congestion\"} 0\n"));
+    }
+}
@@ -0,0 +1,451 @@
+// outgoing.rs - Outgoing peer connection manager.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+extern crate crossbeam_channel;
+extern crate ecdh_wrapper;
+extern crate epoch;
+extern crate mix_link;
+
+use std::cmp;
+use std::io;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::collections::HashMap;
+
+use crossbeam_channel::{Receiver, Sender, Select, RecvTimeoutError, unbounded};
+
+use ecdh_wrapper::PrivateKey;
+use epoch::Clock;
+use mix_link::sync::Session;
+use mix_link::messages::{SessionConfig, PeerAuthenticator, ServerAuthenticatorState};
+use mix_link::commands::Command;
+
+use super::packet::Packet;
+use super::pki::{ConsensusStore, Document};
+use super::config::Shutdown;
+use super::metrics::Metrics;
+use super::drops::DropReason;
+use super::trace::{Tracer, TraceEvent};
+use super::errors::OutgoingError;
+use super::constants;
+
+
+/// Delay in milliseconds before the first reconnection attempt.
+const INITIAL_BACKOFF: u64 = 500;
+
+/// Upper bound in milliseconds on the reconnection delay.
+const MAX_BACKOFF: u64 = 60_000;
+
+/// Milliseconds between checks for connectors whose
+/// peer is no longer in the PKI document.
+const PRUNE_INTERVAL: u64 = 60_000;
+
+pub struct OutgoingConfig {
+    pub link_private_key: PrivateKey,
+    pub identity: [u8; 32],
+    pub outgoing_rx: Receiver<Packet>,
+    pub halt_rx: Receiver<bool>,
+    pub consensus: ConsensusStore,
+    pub clock: Clock,
+    pub shutdown: Shutdown,
+    pub metrics: Metrics,
+    pub tracer: Tracer,
+}
+
+#[derive(Clone)]
+struct ConnectorConfig {
+    id: [u8; 32],
+    link_private_key: PrivateKey,
+    identity: [u8; 32],
+    consensus: ConsensusStore,
+    clock: Clock,
+    metrics: Metrics,
+    tracer: Tracer,
+}
+
+pub fn start_outgoing_dispatcher(cfg: OutgoingConfig) -> JoinHandle<()> {
+    thread::spawn(move || {
+        outgoing_dispatcher(cfg)
+    })
+}
+
+/// Connect to the first address the given one resolves to that
+/// accepts a connection within the connect timeout.
+pub fn dial(address: &str) -> io::Result<TcpStream> {
+    let timeout = Duration::from_millis(constants::CONNECT_TIMEOUT);
+    let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "address did not resolve");
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(x) => return Ok(x),
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+fn drop_packet(metrics: &Metrics, tracer: &Tracer, packet: &Packet, reason: DropReason) {
+    metrics.drops().report(reason);
+    tracer.record(packet, TraceEvent::Dropped(reason));
+}
+
+fn start_connector(cfg: ConnectorConfig) -> (Sender<Packet>, JoinHandle<()>) {
+    let (connector_tx, connector_rx) = unbounded();
+    let handle = thread::spawn(move || {
+        connector(cfg, connector_rx)
+    });
+    (connector_tx, handle)
+}
+
+/// Per peer connectors along with their thread handles
+/// so they can be joined on shutdown.
+struct Connectors {
+    peers: HashMap<[u8; 32], Sender<Packet>>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Connectors {
+    fn new() -> Connectors {
+        Connectors {
+            peers: HashMap::new(),
+            handles: vec![],
+        }
+    }
+
+    fn route(&mut self, cfg: &OutgoingConfig, packet: Packet) {
+        let id = match packet.next_hop {
+            Some(ref next_hop) => next_hop.id,
+            None => {
+                drop_packet(&cfg.metrics, &cfg.tracer, &packet, DropReason::NoRoute);
+                return
+            },
+        };
+        if !self.peers.contains_key(&id) {
+            let doc = match cfg.consensus.get(cfg.clock.now().epoch) {
+                Some(x) => x,
+                None => {
+                    debug!("no PKI document for the current epoch");
+                    drop_packet(&cfg.metrics, &cfg.tracer, &packet, DropReason::NoRoute);
+                    return
+                },
+            };
+            if doc.get_node(&id).is_none() {
+                debug!("next hop not in PKI document");
+                drop_packet(&cfg.metrics, &cfg.tracer, &packet, DropReason::NoRoute);
+                return
+            }
+            let connector_cfg = ConnectorConfig {
+                id: id,
+                link_private_key: cfg.link_private_key.clone(),
+                identity: cfg.identity,
+                consensus: cfg.consensus.clone(),
+                clock: cfg.clock.clone(),
+                metrics: cfg.metrics.clone(),
+                tracer: cfg.tracer.clone(),
+            };
+            let (connector_tx, handle) = start_connector(connector_cfg);
+            self.peers.insert(id, connector_tx);
+            self.handles.push(handle);
+        }
+        if let Err(e) = self.peers[&id].send(packet) {
+            warn!("outgoing connector has gone away");
+            drop_packet(&cfg.metrics, &cfg.tracer, &e.into_inner(), DropReason::PeerUnreachable);
+            self.peers.remove(&id);
+        }
+    }
+
+    /// Close the queues of the connectors whose peer has left the
+    /// PKI, they exit on their own and are reaped later.
+    fn prune(&mut self, doc: &Document) {
+        self.peers.retain(|id, _| {
+            let present = doc.get_node(id).is_some();
+            if !present {
+                debug!("closing connector for peer no longer in the PKI document");
+            }
+            present
+        });
+        self.reap();
+    }
+
+    /// Join the connector threads that have exited.
+    fn reap(&mut self) {
+        let (finished, running) = self.handles.drain(..).partition(|x| x.is_finished());
+        self.handles = running;
+        for handle in finished {
+            if let Err(e) = handle.join() {
+                warn!("outgoing connector panicked: {:?}", e);
+            }
+        }
+    }
+
+    /// Close every connector's queue, which makes it send
+    /// `Disconnect` to its peer, and wait for them to exit.
+    fn close(self) {
+        drop(self.peers);
+        for handle in self.handles {
+            if let Err(e) = handle.join() {
+                warn!("outgoing connector panicked: {:?}", e);
+            }
+        }
+    }
+}
+
+fn outgoing_dispatcher(cfg: OutgoingConfig) {
+    let mut connectors = Connectors::new();
+    let mut last_prune = Instant::now();
+    let mut sel = Select::new();
+    let oper1 = sel.recv(&cfg.outgoing_rx);
+    let oper2 = sel.recv(&cfg.halt_rx);
+    loop {
+        if last_prune.elapsed() >= Duration::from_millis(PRUNE_INTERVAL) {
+            match cfg.consensus.get(cfg.clock.now().epoch) {
+                Some(doc) => connectors.prune(&doc),
+                None => connectors.reap(),
+            }
+            last_prune = Instant::now();
+        }
+        let oper = match sel.select_timeout(Duration::from_millis(PRUNE_INTERVAL)) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        match oper.index() {
+            i if i == oper1 => {
+                match oper.recv(&cfg.outgoing_rx) {
+                    Ok(packet) => connectors.route(&cfg, packet),
+                    Err(_) => {
+                        debug!("outgoing queue closed, halting.");
+                        break
+                    },
+                }
+            },
+            i if i == oper2 => {
+                // The halt channel is closed to signal shutdown.
+                let _ = oper.recv(&cfg.halt_rx);
+                // Route the packets still coming from the scheduler.
+                cfg.shutdown.drain("outgoing dispatcher", &cfg.outgoing_rx, |packet| {
+                    connectors.route(&cfg, packet);
+                    true
+                });
+                break
+            },
+            _ => unreachable!(),
+        }
+    }
+    connectors.close();
+}
+
+/// Resolve the peer from the current PKI document and perform
+/// the client side of the link layer handshake.
+fn connect(cfg: &ConnectorConfig) -> Result<Session, OutgoingError> {
+    let doc = match cfg.consensus.get(cfg.clock.now().epoch) {
+        Some(x) => x,
+        None => return Err(OutgoingError::NoDocument),
+    };
+    let desc = match doc.get_node(&cfg.id) {
+        Some(x) => x,
+        None => return Err(OutgoingError::UnknownPeer),
+    };
+    let peer_public_key = desc.link_public_key()?;
+    for address in desc.addresses.iter() {
+        let stream = match dial(address) {
+            Ok(x) => x,
+            Err(e) => {
+                debug!("failed to connect to peer {} at {}: {}", desc.name, address, e);
+                continue
+            },
+        };
+        let mut mix_map = HashMap::new();
+        mix_map.insert(peer_public_key.clone(), true);
+        let session_config = SessionConfig {
+            authenticator: PeerAuthenticator::Server(ServerAuthenticatorState {
+                mix_map: mix_map,
+            }),
+            authentication_key: cfg.link_private_key.clone(),
+            peer_public_key: Some(peer_public_key.clone()),
+            additional_data: cfg.identity.to_vec(),
+        };
+        let mut session = Session::new(session_config, true)?;
+        session.initialize(stream)?;
+        session = session.into_transport_mode()?;
+        session.finalize_handshake()?;
+        info!("connected to peer {} at {}", desc.name, address);
+        return Ok(session)
+    }
+    Err(OutgoingError::ConnectFailed)
+}
+
+fn next_backoff(backoff: u64) -> u64 {
+    cmp::min(backoff * 2, MAX_BACKOFF)
+}
+
+/// Wait out a reconnection delay, returns false if the connector's
+/// queue was closed meanwhile. Packets can not wait for the peer to
+/// come back without violating their scheduled delay, so are dropped.
+fn wait_backoff(cfg: &ConnectorConfig, connector_rx: &Receiver<Packet>, backoff: u64) -> bool {
+    let retry_at = Instant::now() + Duration::from_millis(backoff);
+    loop {
+        let now = Instant::now();
+        if now >= retry_at {
+            return true
+        }
+        match connector_rx.recv_timeout(retry_at - now) {
+            Ok(packet) => drop_packet(&cfg.metrics, &cfg.tracer, &packet, DropReason::PeerUnreachable),
+            Err(RecvTimeoutError::Timeout) => return true,
+            Err(RecvTimeoutError::Disconnected) => return false,
+        }
+    }
+}
+
+fn connector(cfg: ConnectorConfig, connector_rx: Receiver<Packet>) {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let mut session = match connect(&cfg) {
+            Ok(x) => {
+                backoff = INITIAL_BACKOFF;
+                x
+            },
+            Err(e) => {
+                warn!("outgoing connection failed, retrying in {} ms: {}", backoff, e);
+                if !wait_backoff(&cfg, &connector_rx, backoff) {
+                    return
+                }
+                backoff = next_backoff(backoff);
+                continue
+            },
+        };
+
+        loop {
+            let packet = match connector_rx.recv() {
+                Ok(x) => x,
+                Err(_) => {
+                    // The dispatcher is shutting down.
+                    if let Err(e) = session.send_command(&Command::Disconnect{}) {
+                        debug!("failed to send Disconnect to peer: {}", e);
+                    }
+                    session.close();
+                    return
+                },
+            };
+            let cmd = Command::SendPacket {
+                sphinx_packet: packet.raw.to_vec(),
+            };
+            if let Err(e) = session.send_command(&cmd) {
+                warn!("failed to send packet to peer: {}", e);
+                session.close();
+                break
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate rand;
+
+    use self::rand::os::OsRng;
+    use pki::MixDescriptor;
+    use super::*;
+
+    fn descriptor(id: u8) -> MixDescriptor {
+        MixDescriptor {
+            name: format!("mix{}", id),
+            identity_key: [id; 32],
+            link_key: [0u8; 32],
+            addresses: vec![],
+            is_provider: false,
+            layer: 0,
+            mix_keys: HashMap::new(),
+        }
+    }
+
+    fn connector_config(rng: &mut OsRng) -> ConnectorConfig {
+        ConnectorConfig {
+            id: [1u8; 32],
+            link_private_key: PrivateKey::generate(rng).unwrap(),
+            identity: [2u8; 32],
+            consensus: ConsensusStore::new(),
+            clock: Clock::new_katzenpost(),
+            metrics: Metrics::new(),
+            tracer: Tracer::default(),
+        }
+    }
+
+    #[test]
+    fn connector_backoff_test() {
+        assert_eq!(next_backoff(INITIAL_BACKOFF), 2 * INITIAL_BACKOFF);
+        assert_eq!(next_backoff(MAX_BACKOFF - 1), MAX_BACKOFF);
+        assert_eq!(next_backoff(MAX_BACKOFF), MAX_BACKOFF);
+
+        let cfg = connector_config(&mut OsRng::new().unwrap());
+        let (connector_tx, connector_rx) = unbounded();
+        connector_tx.send(Packet::default()).unwrap();
+        let start = Instant::now();
+        assert!(wait_backoff(&cfg, &connector_rx, 50));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(connector_rx.try_recv().is_err());
+        assert_eq!(cfg.metrics.drops().count(DropReason::PeerUnreachable), 1);
+        drop(connector_tx);
+        assert!(!wait_backoff(&cfg, &connector_rx, 60_000));
+    }
+
+    #[test]
+    fn connector_reconnect_exit_test() {
+        // Without a PKI document every connection attempt fails, the
+        // connector keeps retrying until its queue is closed.
+        let cfg = connector_config(&mut OsRng::new().unwrap());
+        let metrics = cfg.metrics.clone();
+        let (connector_tx, handle) = start_connector(cfg);
+        thread::sleep(Duration::from_millis(2 * INITIAL_BACKOFF));
+        connector_tx.send(Packet::default()).unwrap();
+        drop(connector_tx);
+        handle.join().unwrap();
+        assert_eq!(metrics.drops().count(DropReason::PeerUnreachable), 1);
+    }
+
+    #[test]
+    fn connectors_prune_test() {
+        let mut connectors = Connectors::new();
+        let (stale_tx, stale_rx) = unbounded();
+        let (current_tx, current_rx) = unbounded::<Packet>();
+        connectors.peers.insert([1u8; 32], stale_tx);
+        connectors.peers.insert([2u8; 32], current_tx);
+        let finished = thread::spawn(|| {});
+        while !finished.is_finished() {
+            thread::yield_now();
+        }
+        let (running_tx, running_rx) = unbounded::<bool>();
+        connectors.handles.push(finished);
+        connectors.handles.push(thread::spawn(move || {
+            let _ = running_rx.recv();
+        }));
+        let doc = Document {
+            epoch: 1,
+            topology: vec![vec![descriptor(2)]],
+            providers: vec![],
+        };
+        connectors.prune(&doc);
+        assert!(!connectors.peers.contains_key(&[1u8; 32]));
+        assert!(connectors.peers.contains_key(&[2u8; 32]));
+        assert!(stale_rx.recv().is_err());
+        assert!(current_rx.try_recv().is_err());
+        // Only the connector threads which exited are reaped.
+        assert_eq!(connectors.handles.len(), 1);
+        drop(running_tx);
+        connectors.close();
+    }
+}
@@ -18,11 +18,50 @@ extern crate sphinxcrypto;
 
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::default::Default;
-use sphinxcrypto::constants::PACKET_SIZE;
+use std::sync::atomic::{AtomicU64, Ordering};
+use sphinxcrypto::client::new_packet_from_surb;
+use sphinxcrypto::constants::{PACKET_SIZE, FORWARD_PAYLOAD_SIZE, MAC_SIZE};
 use sphinxcrypto::commands::{RoutingCommand, NextHop, Recipient, SURBReply, Delay};
 use super::errors::PacketError;
 
 
+/// Source of packet IDs, zero is left for packets which never got one.
+static NEXT_PACKET_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Returns an ID unique to this process.
+fn next_packet_id() -> u64 {
+    NEXT_PACKET_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The route a packet takes once unwrapped, determined by
+/// the exact set of routing commands it carries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PacketKind {
+    /// NextHop and Delay, to be sent on to another node.
+    Forward,
+    /// Recipient and Delay, to be spooled and acknowledged.
+    ToUser,
+    /// Recipient alone, to be spooled without acknowledgement.
+    UnreliableToUser,
+    /// Recipient and SURBReply, a reply sent using a SURB.
+    SurbReply,
+    /// Any other combination of routing commands.
+    Invalid,
+}
+
+impl PacketKind {
+    /// Returns the name used in logs and traces.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            PacketKind::Forward => "forward",
+            PacketKind::ToUser => "to_user",
+            PacketKind::UnreliableToUser => "unreliable_to_user",
+            PacketKind::SurbReply => "surb_reply",
+            PacketKind::Invalid => "invalid",
+        }
+    }
+}
+
 pub struct Packet {
     pub id: u64,
     pub raw: Box<[u8; PACKET_SIZE]>,
@@ -35,6 +74,8 @@ pub struct Packet {
     pub delay: u64,
     pub must_forward: bool,
     pub must_terminate: bool,
+    pub traced: bool,
+    num_commands: usize,
 }
 
 impl Default for Packet {
@@ -51,6 +92,8 @@ impl Default for Packet {
             delay: 0,
             must_forward: false,
             must_terminate: false,
+            traced: false,
+            num_commands: 0,
         }
     }
 }
@@ -71,7 +114,7 @@ impl Packet {
         let in_ms = now.as_secs() * 1000 +
             now.subsec_nanos() as u64 / 1_000_000;
         Ok(Packet{
-            id: 0, // XXX - FIX ME?
+            id: next_packet_id(),
             raw: payload,
             receive_time: in_ms,
             payload: None,
@@ -82,6 +125,8 @@ impl Packet {
             delay: 0,
             must_forward: false,
             must_terminate: false,
+            traced: false,
+            num_commands: 0,
         })
     }
 
@@ -91,6 +136,7 @@ impl Packet {
 
     pub fn set_commands(&mut self, cmds: Vec<RoutingCommand>) {
         for cmd in cmds.iter() {
+            self.num_commands += 1;
             match cmd {
                 RoutingCommand::NextHop(next_hop) => {
                     self.next_hop = Some(next_hop.clone());
@@ -108,23 +154,84 @@ impl Packet {
         }
     }
 
-    pub fn is_forward(&self) -> bool {
-        self.next_hop.is_none() && self.delay_cmd.is_some() &&
-            self.recipient.is_some() && self.surb_reply.is_none()
+    /// Classify an unwrapped packet by its routing commands. A
+    /// packet which carried the same command more than once is an error.
+    pub fn classify(&self) -> Result<PacketKind, PacketError> {
+        let present = [
+            self.next_hop.is_some(),
+            self.delay_cmd.is_some(),
+            self.recipient.is_some(),
+            self.surb_reply.is_some(),
+        ];
+        if present.iter().filter(|x| **x).count() < self.num_commands {
+            return Err(PacketError::DuplicateCommand)
+        }
+        let kind = match present {
+            [true, true, false, false] => PacketKind::Forward,
+            [false, true, true, false] => PacketKind::ToUser,
+            [false, false, true, false] => PacketKind::UnreliableToUser,
+            [false, false, true, true] => PacketKind::SurbReply,
+            _ => PacketKind::Invalid,
+        };
+        Ok(kind)
+    }
+}
+
+/// Build the packet carrying a reply back through a SURB, sent
+/// by providers for SURB-ACKs and Kaetzchen responses.
+pub fn new_surb_reply(surb: Vec<u8>, reply: &[u8]) -> Result<Packet, PacketError> {
+    if reply.len() > FORWARD_PAYLOAD_SIZE {
+        return Err(PacketError::ReplyTooLarge(reply.len()))
+    }
+    let mut payload = reply.to_vec();
+    payload.resize(FORWARD_PAYLOAD_SIZE, 0);
+    let (raw, first_hop) = new_packet_from_surb(surb, payload)
+        .map_err(|e| PacketError::SphinxError(format!("{:?}", e)))?;
+    let mut packet = Packet::new(&raw)?;
+    packet.next_hop = Some(NextHop {
+        id: first_hop,
+        mac: [0u8; MAC_SIZE],
+    });
+    Ok(packet)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use sphinxcrypto::constants::{MAC_SIZE, RECIPIENT_ID_SIZE, SURB_ID_SIZE};
+    use super::*;
+
+    fn next_hop() -> RoutingCommand {
+        RoutingCommand::NextHop(NextHop { id: [0u8; 32], mac: [0u8; MAC_SIZE] })
+    }
+
+    fn delay() -> RoutingCommand {
+        RoutingCommand::Delay(Delay { delay: 10 })
+    }
+
+    fn recipient() -> RoutingCommand {
+        RoutingCommand::Recipient(Recipient { id: [0u8; RECIPIENT_ID_SIZE] })
     }
 
-    pub fn is_to_user(&self) -> bool {
-        self.next_hop.is_none() && self.delay_cmd.is_some() &&
-            self.recipient.is_some() && self.surb_reply.is_none()
+    fn surb_reply() -> RoutingCommand {
+        RoutingCommand::SURBReply(SURBReply { id: [0u8; SURB_ID_SIZE] })
     }
 
-    pub fn is_unreliable_to_user(&self) -> bool {
-        self.next_hop.is_none() && self.delay_cmd.is_none() && self.recipient.is_some() &&
-            self.surb_reply.is_none()
+    fn classify(cmds: Vec<RoutingCommand>) -> Result<PacketKind, PacketError> {
+        let mut packet = Packet::default();
+        packet.set_commands(cmds);
+        packet.classify()
     }
 
-    pub fn is_surb_reply(&self) -> bool {
-        self.next_hop.is_none() && self.delay_cmd.is_none() &&
-            self.recipient.is_some() && self.surb_reply.is_some()
+    #[test]
+    fn packet_classify_test() {
+        assert_eq!(classify(vec![next_hop(), delay()]).unwrap(), PacketKind::Forward);
+        assert_eq!(classify(vec![recipient(), delay()]).unwrap(), PacketKind::ToUser);
+        assert_eq!(classify(vec![recipient()]).unwrap(), PacketKind::UnreliableToUser);
+        assert_eq!(classify(vec![recipient(), surb_reply()]).unwrap(), PacketKind::SurbReply);
+        assert_eq!(classify(vec![next_hop()]).unwrap(), PacketKind::Invalid);
+        assert_eq!(classify(vec![next_hop(), delay(), recipient()]).unwrap(), PacketKind::Invalid);
+        assert_eq!(classify(vec![]).unwrap(), PacketKind::Invalid);
+        assert!(classify(vec![next_hop(), delay(), delay()]).is_err());
     }
 }
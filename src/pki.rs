@@ -0,0 +1,308 @@
+// pki.rs - Mix network PKI document types.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! PKI consensus document types and the shared consensus store.
+
+extern crate ecdh_wrapper;
+extern crate ed25519_dalek;
+extern crate sha2;
+extern crate serde_cbor;
+extern crate rustc_serialize;
+
+use std::sync::{Arc, RwLock};
+use std::collections::{BTreeMap, HashMap};
+
+use ecdh_wrapper::PublicKey;
+use ecdh_wrapper::errors::KeyError;
+use ed25519_dalek::{Keypair, PublicKey as IdentityPublicKey, Signature};
+use sha2::Sha512;
+use rustc_serialize::base64::FromBase64;
+
+use super::errors::PkiError;
+
+
+/// Decode a public key from its raw byte representation.
+pub fn public_key_from_bytes(raw: &[u8]) -> Result<PublicKey, KeyError> {
+    let mut key = PublicKey::default();
+    key.from_bytes(raw)?;
+    Ok(key)
+}
+
+/// Returns the raw byte representation of a public key.
+pub fn public_key_to_bytes(key: &PublicKey) -> [u8; 32] {
+    let mut raw = [0u8; 32];
+    raw.copy_from_slice(&key.to_vec());
+    raw
+}
+
+/// Decode a base64 encoded link public key as found in the configuration.
+pub fn link_key_from_base64(encoded: &str) -> Result<PublicKey, PkiError> {
+    let raw = encoded.from_base64().map_err(|e| PkiError::DecodeError(format!("{}", e)))?;
+    Ok(public_key_from_bytes(&raw)?)
+}
+
+/// Decode a base64 encoded identity public key as found in the configuration.
+pub fn identity_key_from_base64(encoded: &str) -> Result<IdentityPublicKey, PkiError> {
+    let raw = encoded.from_base64().map_err(|e| PkiError::DecodeError(format!("{}", e)))?;
+    IdentityPublicKey::from_bytes(&raw).map_err(|e| PkiError::DecodeError(format!("{:?}", e)))
+}
+
+/// A mix descriptor as published in the consensus document.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MixDescriptor {
+    pub name: String,
+    pub identity_key: [u8; 32],
+    pub link_key: [u8; 32],
+    pub addresses: Vec<String>,
+    pub is_provider: bool,
+    pub layer: u8,
+    pub mix_keys: HashMap<u64, [u8; 32]>,
+}
+
+impl MixDescriptor {
+    /// The node ID used in `NextHop` routing commands,
+    /// which is the node's identity public key.
+    pub fn id(&self) -> [u8; 32] {
+        self.identity_key
+    }
+
+    pub fn link_public_key(&self) -> Result<PublicKey, KeyError> {
+        public_key_from_bytes(&self.link_key)
+    }
+}
+
+/// A consensus document describing the network topology for one epoch.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Document {
+    pub epoch: u64,
+    pub topology: Vec<Vec<MixDescriptor>>,
+    pub providers: Vec<MixDescriptor>,
+}
+
+impl Document {
+    /// Returns every descriptor in the document, mixes and providers.
+    pub fn nodes(&self) -> Vec<&MixDescriptor> {
+        let mut nodes: Vec<&MixDescriptor> = self.topology.iter().flat_map(|layer| layer.iter()).collect();
+        nodes.extend(self.providers.iter());
+        nodes
+    }
+
+    pub fn get_node(&self, id: &[u8; 32]) -> Option<&MixDescriptor> {
+        self.nodes().into_iter().find(|x| &x.id() == id)
+    }
+}
+
+/// A signature over a signed document payload.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DocumentSignature {
+    pub public_key: [u8; 32],
+    pub signature: Vec<u8>,
+}
+
+/// A signed PKI document: a CBOR encoded `Document` as published by
+/// the authorities or a CBOR encoded `MixDescriptor` as uploaded by
+/// a mix, along with the signatures over it.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SignedDocument {
+    pub payload: Vec<u8>,
+    pub signatures: Vec<DocumentSignature>,
+}
+
+impl SignedDocument {
+    pub fn sign(payload: Vec<u8>, keypair: &Keypair) -> SignedDocument {
+        let signature = keypair.sign::<Sha512>(&payload);
+        SignedDocument {
+            signatures: vec![DocumentSignature {
+                public_key: keypair.public.to_bytes(),
+                signature: signature.to_bytes().to_vec(),
+            }],
+            payload: payload,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, PkiError> {
+        serde_cbor::to_vec(self).map_err(|e| PkiError::EncodeError(format!("{}", e)))
+    }
+
+    pub fn from_bytes(raw: &[u8]) -> Result<SignedDocument, PkiError> {
+        serde_cbor::from_slice(raw).map_err(|e| PkiError::DecodeError(format!("{}", e)))
+    }
+
+    /// Returns true if the payload carries a valid signature
+    /// by the given authority identity key.
+    pub fn is_signed_by(&self, key: &IdentityPublicKey) -> bool {
+        let raw_key = key.to_bytes();
+        for sig in self.signatures.iter().filter(|x| x.public_key == raw_key) {
+            if let Ok(signature) = Signature::from_bytes(&sig.signature) {
+                if key.verify::<Sha512>(&self.payload, &signature).is_ok() {
+                    return true
+                }
+            }
+        }
+        false
+    }
+
+    pub fn document(&self) -> Result<Document, PkiError> {
+        serde_cbor::from_slice(&self.payload).map_err(|e| PkiError::DecodeError(format!("{}", e)))
+    }
+}
+
+/// The number of epochs prior to the current one
+/// whose consensus documents are kept in the store.
+pub const PAST_EPOCHS: u64 = 1;
+
+/// The status of a consensus request, sent as the
+/// error code of the `Consensus` wire protocol command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsensusStatus {
+    Ok = 0,
+    NotYetAvailable = 1,
+    Gone = 2,
+}
+
+impl ConsensusStatus {
+    pub fn error_code(&self) -> u8 {
+        *self as u8
+    }
+}
+
+struct ConsensusEntry {
+    document: Arc<Document>,
+    raw: Arc<Vec<u8>>,
+}
+
+/// ConsensusStore is a cheaply cloneable handle to the consensus
+/// documents known to this server, indexed by epoch. Both the parsed
+/// document and the signed document as received from the PKI are kept
+/// so that the latter can be served to clients verbatim.
+#[derive(Clone, Default)]
+pub struct ConsensusStore {
+    entries: Arc<RwLock<BTreeMap<u64, ConsensusEntry>>>,
+}
+
+impl ConsensusStore {
+    pub fn new() -> ConsensusStore {
+        ConsensusStore::default()
+    }
+
+    /// Returns true if both handles refer to the same store.
+    pub fn same_store(&self, other: &ConsensusStore) -> bool {
+        Arc::ptr_eq(&self.entries, &other.entries)
+    }
+
+    pub fn insert(&self, document: Document, raw: Vec<u8>) {
+        let mut entries = self.entries.write().unwrap();
+        entries.insert(document.epoch, ConsensusEntry {
+            document: Arc::new(document),
+            raw: Arc::new(raw),
+        });
+    }
+
+    pub fn contains(&self, epoch: u64) -> bool {
+        self.entries.read().unwrap().contains_key(&epoch)
+    }
+
+    pub fn get(&self, epoch: u64) -> Option<Arc<Document>> {
+        let entries = self.entries.read().unwrap();
+        entries.get(&epoch).map(|x| x.document.clone())
+    }
+
+    /// Returns the signed consensus document for the given epoch,
+    /// or the reason it can not be served.
+    pub fn get_signed(&self, epoch: u64, current_epoch: u64) -> Result<Arc<Vec<u8>>, ConsensusStatus> {
+        if epoch + PAST_EPOCHS < current_epoch {
+            return Err(ConsensusStatus::Gone)
+        }
+        let entries = self.entries.read().unwrap();
+        match entries.get(&epoch) {
+            Some(x) => Ok(x.raw.clone()),
+            None => Err(ConsensusStatus::NotYetAvailable),
+        }
+    }
+
+    /// Remove the documents which fell out of the cache window.
+    pub fn prune(&self, current_epoch: u64) {
+        let mut entries = self.entries.write().unwrap();
+        let stale: Vec<u64> = entries.keys().cloned().filter(|x| x + PAST_EPOCHS < current_epoch).collect();
+        for epoch in stale {
+            entries.remove(&epoch);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SecretKey;
+    use super::*;
+
+    fn keypair(seed: u8) -> Keypair {
+        let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+        let public = IdentityPublicKey::from_secret::<Sha512>(&secret);
+        Keypair {
+            secret: secret,
+            public: public,
+        }
+    }
+
+    fn empty_document(epoch: u64) -> Document {
+        Document {
+            epoch: epoch,
+            topology: vec![],
+            providers: vec![],
+        }
+    }
+
+    #[test]
+    fn consensus_store_window_test() {
+        let store = ConsensusStore::new();
+        store.insert(empty_document(9), vec![9]);
+        store.insert(empty_document(10), vec![10]);
+
+        assert_eq!(*store.get_signed(10, 10).unwrap(), vec![10]);
+        assert_eq!(*store.get_signed(9, 10).unwrap(), vec![9]);
+        assert_eq!(store.get_signed(11, 10), Err(ConsensusStatus::NotYetAvailable));
+        assert_eq!(store.get_signed(8, 10), Err(ConsensusStatus::Gone));
+
+        store.prune(11);
+        assert!(!store.contains(9));
+        assert!(store.contains(10));
+        assert_eq!(store.get_signed(9, 11), Err(ConsensusStatus::Gone));
+    }
+
+    #[test]
+    fn signed_document_test() {
+        let key = keypair(1);
+        let payload = serde_cbor::to_vec(&empty_document(3)).unwrap();
+        let signed = SignedDocument::sign(payload, &key);
+        let decoded = SignedDocument::from_bytes(&signed.to_bytes().unwrap()).unwrap();
+        assert!(decoded.is_signed_by(&key.public));
+        assert_eq!(decoded.document().unwrap().epoch, 3);
+        assert!(!decoded.is_signed_by(&keypair(2).public));
+
+        let mut tampered = decoded.clone();
+        tampered.payload.push(0);
+        assert!(!tampered.is_signed_by(&key.public));
+
+        let mut bad_signature = decoded.clone();
+        bad_signature.signatures[0].signature[0] ^= 1;
+        assert!(!bad_signature.is_signed_by(&key.public));
+
+        let mut truncated = decoded;
+        truncated.signatures[0].signature.truncate(32);
+        assert!(!truncated.is_signed_by(&key.public));
+    }
+}
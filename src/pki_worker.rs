@@ -0,0 +1,516 @@
+// pki_worker.rs - PKI client worker.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+extern crate crossbeam_channel;
+extern crate ecdh_wrapper;
+extern crate ed25519_dalek;
+extern crate epoch;
+extern crate mix_link;
+extern crate rand;
+
+use std::cmp;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::net::TcpStream;
+use std::collections::{HashMap, HashSet};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use rand::{Rng, thread_rng};
+
+use ecdh_wrapper::{PrivateKey, PublicKey};
+use ed25519_dalek::PublicKey as IdentityPublicKey;
+use epoch::Clock;
+use mix_link::sync::Session;
+use mix_link::messages::{SessionConfig, PeerAuthenticator, ServerAuthenticatorState};
+use mix_link::commands::Command;
+
+use super::config::{Pki, Nonvoting, Voting};
+use super::pki::{Document, SignedDocument, ConsensusStore, ConsensusStatus,
+                 link_key_from_base64, identity_key_from_base64};
+use super::descriptor::DescriptorBuilder;
+use super::wire_worker::{PeerAuthenticatorBuilder, SessionTracker};
+use super::outgoing::dial;
+use super::errors::PkiError;
+
+
+/// Seconds to wait before trying again to fetch a missing document.
+const RECHECK_INTERVAL: u64 = 60;
+
+/// A PKI authority's addresses and keys.
+#[derive(Clone)]
+pub struct Authority {
+    pub addresses: Vec<String>,
+    pub identity_key: IdentityPublicKey,
+    pub link_key: PublicKey,
+}
+
+impl Authority {
+    pub fn new(addresses: Vec<String>, identity_key: &str, link_key: &str) -> Result<Authority, PkiError> {
+        Ok(Authority {
+            addresses: addresses,
+            identity_key: identity_key_from_base64(identity_key)?,
+            link_key: link_key_from_base64(link_key)?,
+        })
+    }
+
+    fn connect(&self, link_private_key: &PrivateKey, identity: &[u8; 32]) -> Result<Session, PkiError> {
+        for address in self.addresses.iter() {
+            let stream = match dial(address) {
+                Ok(x) => x,
+                Err(e) => {
+                    debug!("failed to connect to authority at {}: {}", address, e);
+                    continue
+                },
+            };
+            let mut mix_map = HashMap::new();
+            mix_map.insert(self.link_key.clone(), true);
+            let session_config = SessionConfig {
+                authenticator: PeerAuthenticator::Server(ServerAuthenticatorState {
+                    mix_map: mix_map,
+                }),
+                authentication_key: link_private_key.clone(),
+                peer_public_key: Some(self.link_key.clone()),
+                additional_data: identity.to_vec(),
+            };
+            let mut session = Session::new(session_config, true)?;
+            session.initialize(stream)?;
+            session = session.into_transport_mode()?;
+            session.finalize_handshake()?;
+            return Ok(session)
+        }
+        Err(PkiError::ConnectFailed)
+    }
+
+    /// Send a command to the authority and return its response.
+    fn round_trip(&self, link_private_key: &PrivateKey, identity: &[u8; 32], cmd: &Command) -> Result<Command, PkiError> {
+        let mut session = self.connect(link_private_key, identity)?;
+        if let Err(e) = session.send_command(cmd) {
+            warn!("failed to send command to authority: {}", e);
+            session.close();
+            return Err(PkiError::ConnectFailed)
+        }
+        let response = session.recv_command();
+        session.close();
+        match response {
+            Ok(x) => Ok(x),
+            Err(_) => Err(PkiError::UnexpectedResponse),
+        }
+    }
+
+    /// Fetch the signed consensus document for the given epoch.
+    pub fn fetch_consensus(&self, link_private_key: &PrivateKey, identity: &[u8; 32], epoch: u64) -> Result<Vec<u8>, PkiError> {
+        let cmd = Command::GetConsensus {
+            epoch: epoch,
+        };
+        match self.round_trip(link_private_key, identity, &cmd)? {
+            Command::Consensus {
+                error_code,
+                payload,
+            } => {
+                if error_code != ConsensusStatus::Ok.error_code() {
+                    return Err(PkiError::NotAvailable(error_code))
+                }
+                Ok(payload)
+            },
+            _ => Err(PkiError::UnexpectedResponse),
+        }
+    }
+
+    /// Upload our signed descriptor for the given epoch.
+    pub fn post_descriptor(&self, link_private_key: &PrivateKey, identity: &[u8; 32], epoch: u64, descriptor: &[u8]) -> Result<(), PkiError> {
+        let cmd = Command::PostDescriptor {
+            epoch: epoch,
+            payload: descriptor.to_vec(),
+        };
+        match self.round_trip(link_private_key, identity, &cmd)? {
+            Command::PostDescriptorStatus {
+                error_code,
+            } => {
+                if error_code != 0 {
+                    return Err(PkiError::DescriptorRejected(error_code))
+                }
+                Ok(())
+            },
+            _ => Err(PkiError::UnexpectedResponse),
+        }
+    }
+}
+
+/// A client of a single non-voting PKI authority.
+pub struct NonvotingClient {
+    authority: Authority,
+    link_private_key: PrivateKey,
+    identity: [u8; 32],
+}
+
+impl NonvotingClient {
+    pub fn new(cfg: &Nonvoting, link_private_key: PrivateKey, identity: [u8; 32]) -> Result<NonvotingClient, PkiError> {
+        Ok(NonvotingClient {
+            authority: Authority::new(vec![cfg.address.clone()], &cfg.public_key, &cfg.link_public_key)?,
+            link_private_key: link_private_key,
+            identity: identity,
+        })
+    }
+
+    pub fn get_consensus(&self, epoch: u64) -> Result<(Document, Vec<u8>), PkiError> {
+        let raw = self.authority.fetch_consensus(&self.link_private_key, &self.identity, epoch)?;
+        let document = verify_signed(&raw, &self.authority, epoch)?;
+        Ok((document, raw))
+    }
+
+    pub fn post_descriptor(&self, epoch: u64, descriptor: &[u8]) -> Result<(), PkiError> {
+        self.authority.post_descriptor(&self.link_private_key, &self.identity, epoch, descriptor)
+    }
+}
+
+/// Returns the document if it was signed by the given authority.
+fn verify_signed(raw: &[u8], authority: &Authority, epoch: u64) -> Result<Document, PkiError> {
+    let signed = SignedDocument::from_bytes(raw)?;
+    if !signed.is_signed_by(&authority.identity_key) {
+        return Err(PkiError::InvalidSignature)
+    }
+    let document = signed.document()?;
+    if document.epoch != epoch {
+        return Err(PkiError::InvalidDocument)
+    }
+    Ok(document)
+}
+
+/// Returns the document if it was signed by at least
+/// `threshold` of the given authorities.
+fn verify_threshold(raw: &[u8], authorities: &[Authority], threshold: usize, epoch: u64) -> Result<Document, PkiError> {
+    let signed = SignedDocument::from_bytes(raw)?;
+    let count = authorities.iter().filter(|x| signed.is_signed_by(&x.identity_key)).count();
+    if count < threshold {
+        return Err(PkiError::InsufficientSignatures(count, threshold))
+    }
+    let document = signed.document()?;
+    if document.epoch != epoch {
+        return Err(PkiError::InvalidDocument)
+    }
+    Ok(document)
+}
+
+/// A client of a set of voting PKI authorities. A consensus is
+/// accepted if it is signed by a majority of the authorities.
+pub struct VotingClient {
+    authorities: Vec<Authority>,
+    link_private_key: PrivateKey,
+    identity: [u8; 32],
+}
+
+impl VotingClient {
+    pub fn new(cfg: &Voting, link_private_key: PrivateKey, identity: [u8; 32]) -> Result<VotingClient, PkiError> {
+        let mut authorities: Vec<Authority> = vec![];
+        for peer in cfg.peers.iter() {
+            let authority = Authority::new(peer.addresses.clone(), &peer.identity_public_key, &peer.link_public_key)?;
+            // A duplicated authority must not count twice towards the threshold.
+            if authorities.iter().any(|x| x.identity_key == authority.identity_key) {
+                warn!("ignoring duplicate voting authority {:?}", peer.addresses);
+                continue
+            }
+            authorities.push(authority);
+        }
+        if authorities.is_empty() {
+            return Err(PkiError::NotConfigured)
+        }
+        Ok(VotingClient {
+            authorities: authorities,
+            link_private_key: link_private_key,
+            identity: identity,
+        })
+    }
+
+    fn threshold(&self) -> usize {
+        self.authorities.len() / 2 + 1
+    }
+
+    /// Fetch the consensus from the authorities in turn, starting at a
+    /// random one, until one of them serves a properly signed document.
+    pub fn get_consensus(&self, epoch: u64) -> Result<(Document, Vec<u8>), PkiError> {
+        let start = thread_rng().gen_range(0, self.authorities.len());
+        let mut last_error = PkiError::ConnectFailed;
+        for i in 0..self.authorities.len() {
+            let authority = &self.authorities[(start + i) % self.authorities.len()];
+            let raw = match authority.fetch_consensus(&self.link_private_key, &self.identity, epoch) {
+                Ok(x) => x,
+                Err(e) => {
+                    debug!("authority {:?} failed to serve consensus: {}", authority.addresses, e);
+                    last_error = e;
+                    continue
+                },
+            };
+            match verify_threshold(&raw, &self.authorities, self.threshold(), epoch) {
+                Ok(document) => return Ok((document, raw)),
+                Err(e) => {
+                    warn!("authority {:?} served a bad consensus: {}", authority.addresses, e);
+                    last_error = e;
+                },
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Upload the descriptor to every authority. The upload succeeds
+    /// if enough authorities accepted it to reach a majority.
+    pub fn post_descriptor(&self, epoch: u64, descriptor: &[u8]) -> Result<(), PkiError> {
+        let mut accepted = 0;
+        let mut last_error = PkiError::ConnectFailed;
+        for authority in self.authorities.iter() {
+            match authority.post_descriptor(&self.link_private_key, &self.identity, epoch, descriptor) {
+                Ok(()) => accepted += 1,
+                Err(e) => {
+                    warn!("authority {:?} failed to accept descriptor: {}", authority.addresses, e);
+                    last_error = e;
+                },
+            }
+        }
+        if accepted < self.threshold() {
+            return Err(last_error)
+        }
+        Ok(())
+    }
+}
+
+pub enum PkiClient {
+    Nonvoting(NonvotingClient),
+    Voting(VotingClient),
+}
+
+impl PkiClient {
+    /// Create a client for the configured PKI. The `identity` is our
+    /// identity public key, by which the authorities know this node.
+    pub fn new(cfg: &Pki, link_private_key: PrivateKey, identity: [u8; 32]) -> Result<PkiClient, PkiError> {
+        if let Some(ref x) = cfg.nonvoting {
+            return Ok(PkiClient::Nonvoting(NonvotingClient::new(x, link_private_key, identity)?))
+        }
+        if let Some(ref x) = cfg.voting {
+            return Ok(PkiClient::Voting(VotingClient::new(x, link_private_key, identity)?))
+        }
+        Err(PkiError::NotConfigured)
+    }
+
+    /// Fetch and verify the consensus for the given epoch, returning
+    /// the parsed document along with the signed document.
+    pub fn get_consensus(&self, epoch: u64) -> Result<(Document, Vec<u8>), PkiError> {
+        match *self {
+            PkiClient::Nonvoting(ref client) => client.get_consensus(epoch),
+            PkiClient::Voting(ref client) => client.get_consensus(epoch),
+        }
+    }
+
+    pub fn post_descriptor(&self, epoch: u64, descriptor: &[u8]) -> Result<(), PkiError> {
+        match *self {
+            PkiClient::Nonvoting(ref client) => client.post_descriptor(epoch, descriptor),
+            PkiClient::Voting(ref client) => client.post_descriptor(epoch, descriptor),
+        }
+    }
+}
+
+pub struct PkiWorkerConfig {
+    pub client: PkiClient,
+    pub descriptor_builder: Option<DescriptorBuilder>,
+    pub consensus: ConsensusStore,
+    pub clock: Clock,
+    pub peer_auth_builder: PeerAuthenticatorBuilder,
+    pub sessions: SessionTracker,
+    pub halt_rx: Receiver<bool>,
+}
+
+pub fn start_pki_worker(cfg: PkiWorkerConfig) -> JoinHandle<()> {
+    thread::spawn(move || {
+        pki_worker(cfg)
+    })
+}
+
+fn pki_worker(cfg: PkiWorkerConfig) {
+    let mut posted: HashSet<u64> = HashSet::new();
+    let mut auth_epoch = None;
+    loop {
+        let now = cfg.clock.now();
+
+        // Upload our descriptor for the current and next epochs,
+        // failed uploads are retried on the next pass.
+        let mut post_pending = false;
+        if let Some(ref builder) = cfg.descriptor_builder {
+            posted.retain(|x| *x >= now.epoch);
+            for epoch in vec![now.epoch, now.epoch + 1] {
+                if posted.contains(&epoch) {
+                    continue
+                }
+                match builder.build_signed(epoch).and_then(|x| cfg.client.post_descriptor(epoch, &x)) {
+                    Ok(()) => {
+                        info!("uploaded descriptor for epoch {}", epoch);
+                        posted.insert(epoch);
+                    },
+                    Err(e) => {
+                        warn!("failed to upload descriptor for epoch {}: {}", epoch, e);
+                        post_pending = true;
+                    },
+                }
+            }
+        }
+
+        for epoch in vec![now.epoch, now.epoch + 1] {
+            if cfg.consensus.contains(epoch) {
+                continue
+            }
+            match cfg.client.get_consensus(epoch) {
+                Ok((document, raw)) => {
+                    info!("fetched consensus for epoch {}", epoch);
+                    cfg.consensus.insert(document, raw);
+                },
+                Err(e) => {
+                    warn!("failed to fetch consensus for epoch {}: {}", epoch, e);
+                },
+            }
+        }
+        cfg.consensus.prune(now.epoch);
+
+        // Peers which authenticated under the previous epoch's
+        // document must still be listed in the current one.
+        if auth_epoch != Some(now.epoch) && cfg.consensus.contains(now.epoch) {
+            let closed = cfg.sessions.close_unauthorized(&cfg.peer_auth_builder.build());
+            if closed > 0 {
+                info!("closed {} sessions with peers not authorized in epoch {}", closed, now.epoch);
+            }
+            auth_epoch = Some(now.epoch);
+        }
+
+        // Sleep until the next epoch unless a document
+        // is still missing or an upload must be retried.
+        let wait = if cfg.consensus.contains(now.epoch + 1) && !post_pending {
+            now.till
+        } else {
+            cmp::min(now.till, RECHECK_INTERVAL)
+        };
+        match cfg.halt_rx.recv_timeout(Duration::from_secs(cmp::max(wait, 1))) {
+            Err(RecvTimeoutError::Timeout) => continue,
+            _ => return,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate serde_cbor;
+
+    use ed25519_dalek::{Keypair, SecretKey};
+    use rand::os::OsRng;
+    use rustc_serialize::base64::{ToBase64, STANDARD};
+    use sha2::Sha512;
+    use pki::{Document, DocumentSignature};
+    use super::*;
+
+    fn keypair(seed: u8) -> Keypair {
+        let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+        let public = IdentityPublicKey::from_secret::<Sha512>(&secret);
+        Keypair {
+            secret: secret,
+            public: public,
+        }
+    }
+
+    fn signed_document(epoch: u64, signers: &[&Keypair]) -> Vec<u8> {
+        let document = Document {
+            epoch: epoch,
+            topology: vec![],
+            providers: vec![],
+        };
+        let payload = serde_cbor::to_vec(&document).unwrap();
+        let signatures = signers.iter().map(|x| DocumentSignature {
+            public_key: x.public.to_bytes(),
+            signature: x.sign::<Sha512>(&payload).to_bytes().to_vec(),
+        }).collect();
+        serde_cbor::to_vec(&SignedDocument {
+            payload: payload,
+            signatures: signatures,
+        }).unwrap()
+    }
+
+    #[test]
+    fn voting_threshold_test() {
+        let keys = vec![keypair(1), keypair(2), keypair(3)];
+        let authorities: Vec<Authority> = keys.iter().map(|x| Authority {
+            addresses: vec![],
+            identity_key: x.public,
+            link_key: PublicKey::default(),
+        }).collect();
+        let outsider = keypair(4);
+
+        let raw = signed_document(7, &[&keys[0], &keys[2]]);
+        assert_eq!(verify_threshold(&raw, &authorities, 2, 7).unwrap().epoch, 7);
+        match verify_threshold(&raw, &authorities, 2, 8) {
+            Err(PkiError::InvalidDocument) => {},
+            _ => panic!("wrong epoch accepted"),
+        }
+
+        let raw = signed_document(7, &[&keys[1], &outsider]);
+        match verify_threshold(&raw, &authorities, 2, 7) {
+            Err(PkiError::InsufficientSignatures(1, 2)) => {},
+            _ => panic!("minority consensus accepted"),
+        }
+    }
+
+    #[test]
+    fn nonvoting_signature_test() {
+        let key = keypair(1);
+        let authority = Authority {
+            addresses: vec![],
+            identity_key: key.public,
+            link_key: PublicKey::default(),
+        };
+
+        let raw = signed_document(7, &[&key]);
+        assert_eq!(verify_signed(&raw, &authority, 7).unwrap().epoch, 7);
+        match verify_signed(&raw, &authority, 8) {
+            Err(PkiError::InvalidDocument) => {},
+            _ => panic!("wrong epoch accepted"),
+        }
+
+        let raw = signed_document(7, &[&keypair(2)]);
+        match verify_signed(&raw, &authority, 7) {
+            Err(PkiError::InvalidSignature) => {},
+            _ => panic!("document signed by the wrong key accepted"),
+        }
+
+        let mut signed = SignedDocument::from_bytes(&signed_document(7, &[&key])).unwrap();
+        signed.signatures[0].signature[0] ^= 1;
+        match verify_signed(&signed.to_bytes().unwrap(), &authority, 7) {
+            Err(PkiError::InvalidSignature) => {},
+            _ => panic!("bad signature accepted"),
+        }
+    }
+
+    #[test]
+    fn nonvoting_client_test() {
+        let key = keypair(1);
+        let link_key = PrivateKey::generate(&mut OsRng::new().unwrap()).unwrap();
+        let mut cfg = Nonvoting {
+            address: "127.0.0.1:29484".to_string(),
+            public_key: key.public.to_bytes().to_base64(STANDARD),
+            link_public_key: link_key.public_key().to_vec().to_base64(STANDARD),
+        };
+        let client = NonvotingClient::new(&cfg, link_key.clone(), [0u8; 32]).unwrap();
+        assert_eq!(client.authority.identity_key, key.public);
+        assert_eq!(client.authority.addresses, vec![cfg.address.clone()]);
+
+        cfg.link_public_key = String::new();
+        assert!(NonvotingClient::new(&cfg, link_key, [0u8; 32]).is_err());
+    }
+}
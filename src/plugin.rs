@@ -0,0 +1,541 @@
+// plugin.rs - External Kaetzchen plugins.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Plugins are Kaetzchen run as subprocesses of the provider. Requests
+//! are written to the plugin's stdin and responses read from its stdout,
+//! each message being a CBOR map prefixed by its big endian u32 length.
+//! The SURBs stay with the provider, the plugin only sees an opaque
+//! handle which it echoes back in its response.
+
+extern crate crossbeam_channel;
+extern crate serde_cbor;
+
+use std::cmp;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use std::io::{Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::collections::HashMap;
+
+use byteorder::{BigEndian, ByteOrder};
+use crossbeam_channel::{Receiver, Sender, Select, TrySendError, bounded, unbounded};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_bytes;
+
+use super::packet::{Packet, new_surb_reply};
+use super::config::Plugin;
+use super::kaetzchen::ServiceRequest;
+use super::errors::PluginError;
+
+
+/// Largest message we accept from or send to a plugin.
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Delay in milliseconds before the first restart of a crashed plugin.
+const INITIAL_BACKOFF: u64 = 500;
+
+/// Upper bound in milliseconds on the restart delay.
+const MAX_BACKOFF: u64 = 60_000;
+
+/// Milliseconds a plugin has to respond before its request is forgotten.
+const REQUEST_TIMEOUT: u64 = 60_000;
+
+/// Requests waiting to be written to a plugin, further
+/// requests are dropped until the plugin catches up.
+const WRITE_QUEUE_SIZE: usize = 64;
+
+/// A request as seen by the plugin.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct PluginRequest {
+    pub surb_handle: u64,
+    #[serde(with = "serde_bytes")]
+    pub payload: Vec<u8>,
+}
+
+/// A plugin's response to the request with the same `surb_handle`,
+/// an empty payload means there is nothing to send back.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct PluginResponse {
+    pub surb_handle: u64,
+    #[serde(with = "serde_bytes")]
+    pub payload: Vec<u8>,
+}
+
+/// Encode a length prefixed CBOR message.
+fn encode_frame<T: Serialize>(message: &T) -> Result<Vec<u8>, PluginError> {
+    let encoded = serde_cbor::to_vec(message).map_err(|e| PluginError::EncodeError(format!("{}", e)))?;
+    if encoded.len() > MAX_FRAME_SIZE {
+        return Err(PluginError::FrameTooLarge(encoded.len()))
+    }
+    let mut frame = vec![0u8; 4];
+    BigEndian::write_u32(&mut frame, encoded.len() as u32);
+    frame.extend_from_slice(&encoded);
+    Ok(frame)
+}
+
+/// Write a length prefixed CBOR message.
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<(), PluginError> {
+    writer.write_all(&encode_frame(message)?)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read a length prefixed CBOR message.
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<T, PluginError> {
+    let mut prefix = [0u8; 4];
+    reader.read_exact(&mut prefix)?;
+    let len = BigEndian::read_u32(&prefix) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(PluginError::FrameTooLarge(len))
+    }
+    let mut encoded = vec![0u8; len];
+    reader.read_exact(&mut encoded)?;
+    serde_cbor::from_slice(&encoded).map_err(|e| PluginError::DecodeError(format!("{}", e)))
+}
+
+pub struct PluginConfig {
+    pub plugin: Plugin,
+    pub request_rx: Receiver<ServiceRequest>,
+    pub outgoing_tx: Sender<Packet>,
+    pub halt_rx: Receiver<bool>,
+}
+
+pub fn start_plugin_worker(cfg: PluginConfig) -> JoinHandle<()> {
+    thread::spawn(move || {
+        plugin_worker(cfg)
+    })
+}
+
+/// A running plugin process. Its requests are written and its
+/// responses read by separate threads so a slow plugin never
+/// blocks us.
+struct PluginProcess {
+    child: Child,
+    request_tx: Sender<Vec<u8>>,
+    response_rx: Receiver<PluginResponse>,
+}
+
+impl PluginProcess {
+    fn spawn(plugin: &Plugin) -> Result<PluginProcess, PluginError> {
+        let mut child = Command::new(&plugin.command)
+            .args(&plugin.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+        let (request_tx, request_rx) = bounded::<Vec<u8>>(WRITE_QUEUE_SIZE);
+        let (response_tx, response_rx) = unbounded();
+        let name = plugin.name.clone();
+        thread::spawn(move || {
+            // Exiting drops request_rx, which tells the worker
+            // the plugin has gone away when it next sends.
+            for frame in request_rx.iter() {
+                if let Err(e) = stdin.write_all(&frame).and_then(|_| stdin.flush()) {
+                    debug!("plugin {} stopped reading requests: {}", name, e);
+                    return
+                }
+            }
+        });
+        let name = plugin.name.clone();
+        thread::spawn(move || {
+            // Exiting closes response_tx, which tells the
+            // worker the plugin has gone away.
+            loop {
+                match read_frame(&mut stdout) {
+                    Ok(response) => {
+                        if response_tx.send(response).is_err() {
+                            return
+                        }
+                    },
+                    Err(e) => {
+                        debug!("plugin {} stopped responding: {}", name, e);
+                        return
+                    },
+                }
+            }
+        });
+        Ok(PluginProcess {
+            child: child,
+            request_tx: request_tx,
+            response_rx: response_rx,
+        })
+    }
+
+    fn stop(mut self) {
+        // The writer closes stdin once its queue is closed.
+        drop(self.request_tx);
+        if let Err(e) = self.child.kill() {
+            debug!("failed to kill plugin: {}", e);
+        }
+        let _ = self.child.wait();
+    }
+}
+
+/// The requests a plugin is handling, along with the SURBs
+/// to send their responses with and the time they expire.
+struct InFlight {
+    requests: HashMap<u64, (Option<Vec<u8>>, Instant)>,
+    max_requests: usize,
+    timeout: Duration,
+}
+
+impl InFlight {
+    fn new(max_requests: usize, timeout: Duration) -> InFlight {
+        InFlight {
+            requests: HashMap::new(),
+            max_requests: max_requests,
+            timeout: timeout,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.requests.len() >= self.max_requests
+    }
+
+    fn insert(&mut self, handle: u64, surb: Option<Vec<u8>>) {
+        self.requests.insert(handle, (surb, Instant::now() + self.timeout));
+    }
+
+    fn remove(&mut self, handle: u64) -> Option<Option<Vec<u8>>> {
+        self.requests.remove(&handle).map(|(surb, _)| surb)
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.requests.values().map(|&(_, deadline)| deadline).min()
+    }
+
+    /// Forget the requests which expired, returns how many did.
+    fn expire(&mut self, now: Instant) -> usize {
+        let before = self.requests.len();
+        self.requests.retain(|_, &mut (_, deadline)| deadline > now);
+        before - self.requests.len()
+    }
+}
+
+enum Exit {
+    Halted,
+    Crashed,
+}
+
+/// Serve requests until the plugin crashes or we are halted.
+fn run(cfg: &PluginConfig, process: &mut PluginProcess, backoff: &mut u64) -> Exit {
+    let mut in_flight = InFlight::new(cfg.plugin.max_concurrency, Duration::from_millis(REQUEST_TIMEOUT));
+    let mut next_handle = 0u64;
+    let mut sel = Select::new();
+    let oper1 = sel.recv(&cfg.request_rx);
+    let oper2 = sel.recv(&process.response_rx);
+    let oper3 = sel.recv(&cfg.halt_rx);
+    loop {
+        let oper = match in_flight.next_deadline() {
+            Some(deadline) => {
+                let now = Instant::now();
+                let ready = if deadline > now {
+                    sel.select_timeout(deadline - now).ok()
+                } else {
+                    None
+                };
+                match ready {
+                    Some(x) => x,
+                    None => {
+                        let count = in_flight.expire(Instant::now());
+                        debug!("plugin {} did not respond to {} requests in time", cfg.plugin.name, count);
+                        continue
+                    },
+                }
+            },
+            None => sel.select(),
+        };
+        match oper.index() {
+            i if i == oper1 => {
+                let request = match oper.recv(&cfg.request_rx) {
+                    Ok(x) => x,
+                    Err(_) => return Exit::Halted,
+                };
+                if in_flight.is_full() {
+                    debug!("Dropping request for plugin {}: (too many requests in flight)", cfg.plugin.name);
+                    continue
+                }
+                let handle = next_handle;
+                next_handle = next_handle.wrapping_add(1);
+                let plugin_request = PluginRequest {
+                    surb_handle: handle,
+                    payload: request.payload,
+                };
+                let frame = match encode_frame(&plugin_request) {
+                    Ok(x) => x,
+                    Err(PluginError::FrameTooLarge(len)) => {
+                        debug!("Dropping request for plugin {}: ({} byte request)", cfg.plugin.name, len);
+                        continue
+                    },
+                    Err(e) => {
+                        warn!("failed to encode request for plugin {}: {}", cfg.plugin.name, e);
+                        continue
+                    },
+                };
+                match process.request_tx.try_send(frame) {
+                    Ok(()) => {
+                        in_flight.insert(handle, request.surb);
+                    },
+                    Err(TrySendError::Full(_)) => {
+                        debug!("Dropping request for plugin {}: (plugin is not reading requests)", cfg.plugin.name);
+                    },
+                    Err(TrySendError::Disconnected(_)) => {
+                        warn!("failed to send request to plugin {}: stdin closed", cfg.plugin.name);
+                        return Exit::Crashed
+                    },
+                }
+            },
+            i if i == oper2 => {
+                let response = match oper.recv(&process.response_rx) {
+                    Ok(x) => x,
+                    Err(_) => return Exit::Crashed,
+                };
+                *backoff = INITIAL_BACKOFF;
+                let surb = match in_flight.remove(response.surb_handle) {
+                    Some(x) => x,
+                    None => {
+                        debug!("plugin {} responded to unknown request {}", cfg.plugin.name, response.surb_handle);
+                        continue
+                    },
+                };
+                if response.payload.is_empty() {
+                    continue
+                }
+                let surb = match surb {
+                    Some(x) => x,
+                    None => {
+                        debug!("Dropping plugin {} response: (request carried no SURB)", cfg.plugin.name);
+                        continue
+                    },
+                };
+                match new_surb_reply(surb, &response.payload) {
+                    Ok(reply) => {
+                        if cfg.outgoing_tx.send(reply).is_err() {
+                            return Exit::Halted
+                        }
+                    },
+                    Err(e) => debug!("failed to build plugin {} reply: {}", cfg.plugin.name, e),
+                }
+            },
+            i if i == oper3 => {
+                // The halt channel is closed to signal shutdown.
+                let _ = oper.recv(&cfg.halt_rx);
+                return Exit::Halted
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Drop requests until `retry_at`, returns false if halted meanwhile.
+fn wait_for_restart(cfg: &PluginConfig, retry_at: Instant) -> bool {
+    let mut sel = Select::new();
+    let oper1 = sel.recv(&cfg.request_rx);
+    let oper2 = sel.recv(&cfg.halt_rx);
+    loop {
+        let now = Instant::now();
+        if now >= retry_at {
+            return true
+        }
+        let oper = match sel.select_timeout(retry_at - now) {
+            Ok(x) => x,
+            Err(_) => return true,
+        };
+        match oper.index() {
+            i if i == oper1 => {
+                if oper.recv(&cfg.request_rx).is_err() {
+                    return false
+                }
+                debug!("Dropping request for plugin {}: (plugin is restarting)", cfg.plugin.name);
+            },
+            i if i == oper2 => {
+                let _ = oper.recv(&cfg.halt_rx);
+                return false
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn plugin_worker(cfg: PluginConfig) {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        match PluginProcess::spawn(&cfg.plugin) {
+            Ok(mut process) => {
+                info!("started plugin {}", cfg.plugin.name);
+                let exit = run(&cfg, &mut process, &mut backoff);
+                process.stop();
+                if let Exit::Halted = exit {
+                    return
+                }
+                // Requests still in flight are lost with the process.
+                warn!("plugin {} exited, restarting in {} ms", cfg.plugin.name, backoff);
+            },
+            Err(e) => {
+                warn!("failed to start plugin {}, retrying in {} ms: {}", cfg.plugin.name, backoff, e);
+            },
+        }
+        if !wait_for_restart(&cfg, Instant::now() + Duration::from_millis(backoff)) {
+            return
+        }
+        backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate ecdh_wrapper;
+    extern crate rand;
+    extern crate sphinxcrypto;
+    extern crate tempfile;
+
+    use std::fs;
+    use std::io::Cursor;
+    use self::ecdh_wrapper::PrivateKey;
+    use self::rand::os::OsRng;
+    use self::sphinxcrypto::client::{new_surb, PathHop};
+    use self::sphinxcrypto::commands::{RoutingCommand, SURBReply};
+    use self::sphinxcrypto::constants::SURB_ID_SIZE;
+    use self::tempfile::TempDir;
+    use super::*;
+
+    fn shell_plugin(script: &str, max_concurrency: usize) -> Plugin {
+        Plugin {
+            name: "test".to_string(),
+            command: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            max_concurrency: max_concurrency,
+        }
+    }
+
+    /// A SURB whose first hop identifies the reply sent with it.
+    fn surb(rng: &mut OsRng, first_hop: u8) -> Vec<u8> {
+        let key = PrivateKey::generate(rng).unwrap();
+        let hop = PathHop {
+            id: [first_hop; 32],
+            public_key: key.public_key(),
+            commands: Some(vec![RoutingCommand::SURBReply(SURBReply { id: [2u8; SURB_ID_SIZE] })]),
+        };
+        new_surb(rng, vec![hop]).unwrap().0
+    }
+
+    #[test]
+    fn plugin_frame_roundtrip_test() {
+        let request = PluginRequest {
+            surb_handle: 7,
+            payload: b"hello".to_vec(),
+        };
+        let mut buf = vec![];
+        write_frame(&mut buf, &request).unwrap();
+        assert_eq!(BigEndian::read_u32(&buf[..4]) as usize, buf.len() - 4);
+        let decoded: PluginRequest = read_frame(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(decoded, request);
+
+        let mut oversized = vec![0xffu8; 4];
+        oversized.extend_from_slice(&[0u8; 8]);
+        match read_frame::<_, PluginResponse>(&mut Cursor::new(oversized)) {
+            Err(PluginError::FrameTooLarge(_)) => {},
+            _ => panic!("oversized frame accepted"),
+        }
+    }
+
+    #[test]
+    fn plugin_in_flight_test() {
+        let mut in_flight = InFlight::new(2, Duration::from_millis(50));
+        assert_eq!(in_flight.next_deadline(), None);
+        in_flight.insert(0, Some(vec![0]));
+        in_flight.insert(1, None);
+        assert!(in_flight.is_full());
+        assert_eq!(in_flight.remove(0), Some(Some(vec![0])));
+        assert_eq!(in_flight.remove(0), None);
+        assert!(!in_flight.is_full());
+
+        let deadline = in_flight.next_deadline().unwrap();
+        assert_eq!(in_flight.expire(deadline - Duration::from_millis(1)), 0);
+        assert_eq!(in_flight.expire(deadline), 1);
+        assert_eq!(in_flight.remove(1), None);
+    }
+
+    #[test]
+    fn plugin_restart_test() {
+        let dir = TempDir::new().unwrap();
+        let log = dir.path().join("starts");
+        let (_request_tx, request_rx) = unbounded();
+        let (outgoing_tx, _outgoing_rx) = unbounded();
+        let (halt_tx, halt_rx) = unbounded();
+        let worker = start_plugin_worker(PluginConfig {
+            plugin: shell_plugin(&format!("echo started >> {}", log.to_str().unwrap()), 1),
+            request_rx: request_rx,
+            outgoing_tx: outgoing_tx,
+            halt_rx: halt_rx,
+        });
+
+        // The plugin exits right away and is restarted after the backoff.
+        thread::sleep(Duration::from_millis(INITIAL_BACKOFF * 3));
+        drop(halt_tx);
+        worker.join().unwrap();
+        let starts = fs::read_to_string(&log).unwrap().lines().count();
+        assert!(starts >= 2, "plugin started {} times", starts);
+    }
+
+    #[test]
+    fn plugin_concurrency_test() {
+        let dir = TempDir::new().unwrap();
+        let fifo = dir.path().join("release");
+        assert!(Command::new("mkfifo").arg(&fifo).status().unwrap().success());
+        let mut rng = OsRng::new().unwrap();
+        let (request_tx, request_rx) = unbounded();
+        let (outgoing_tx, outgoing_rx) = unbounded();
+        let (halt_tx, halt_rx) = unbounded();
+        // The plugin reads nothing until released through the fifo, then
+        // cat echoes requests back, which have the shape of responses.
+        let worker = start_plugin_worker(PluginConfig {
+            plugin: shell_plugin(&format!("read x < {}; exec cat", fifo.to_str().unwrap()), 2),
+            request_rx: request_rx,
+            outgoing_tx: outgoing_tx,
+            halt_rx: halt_rx,
+        });
+        let request = |rng: &mut OsRng, first_hop: u8| ServiceRequest {
+            payload: b"hello".to_vec(),
+            surb: Some(surb(rng, first_hop)),
+        };
+        for first_hop in 1..4 {
+            request_tx.send(request(&mut rng, first_hop)).unwrap();
+        }
+
+        // Once the worker has taken all three requests the third was
+        // dropped for exceeding the cap, as nothing has been answered.
+        while !request_tx.is_empty() {
+            thread::yield_now();
+        }
+        fs::write(&fifo, b"go\n").unwrap();
+        for first_hop in 1..3 {
+            let reply = outgoing_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(reply.next_hop.unwrap().id, [first_hop; 32]);
+        }
+
+        // Had the third been written, its reply would come first.
+        request_tx.send(request(&mut rng, 4)).unwrap();
+        let reply = outgoing_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(reply.next_hop.unwrap().id, [4u8; 32]);
+        drop(halt_tx);
+        worker.join().unwrap();
+    }
+}
@@ -0,0 +1,255 @@
+// provider.rs - Provider packet processing worker.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+extern crate crossbeam_channel;
+extern crate sphinxcrypto;
+
+use std::thread;
+use std::thread::JoinHandle;
+
+use crossbeam_channel::{Receiver, Sender, Select};
+use sphinxcrypto::constants::SURB_SIZE;
+
+use super::packet::{Packet, PacketKind, new_surb_reply};
+use super::spool::{UserSpool, SpoolMessage, normalize_recipient};
+use super::errors::PacketError;
+use super::config::Shutdown;
+use super::metrics::Metrics;
+use super::drops::DropReason;
+use super::trace::{Tracer, TraceEvent};
+use super::constants;
+
+
+pub struct ProviderConfig {
+    pub provider_rx: Receiver<Packet>,
+    pub outgoing_tx: Sender<Packet>,
+    pub halt_rx: Receiver<bool>,
+    pub spool: UserSpool,
+    pub shutdown: Shutdown,
+    pub metrics: Metrics,
+    pub tracer: Tracer,
+}
+
+pub fn start_provider_worker(cfg: ProviderConfig) -> JoinHandle<()> {
+    thread::spawn(move || {
+        provider_worker(cfg)
+    })
+}
+
+/// Split a forward payload into the user message and the
+/// optional SURB carried after the plaintext header.
+pub fn parse_forward_payload(payload: &[u8]) -> Result<(Vec<u8>, Option<Vec<u8>>), PacketError> {
+    let header_len = constants::SPHINX_PLAINTEXT_HEADER_SIZE;
+    if payload.len() < header_len + SURB_SIZE {
+        return Err(PacketError::InvalidPayload)
+    }
+    if payload[1] != 0 {
+        return Err(PacketError::InvalidPayload)
+    }
+    let surb = match payload[0] {
+        0 => None,
+        constants::SURB_FLAG => Some(payload[header_len..header_len + SURB_SIZE].to_vec()),
+        _ => return Err(PacketError::InvalidPayload),
+    };
+    Ok((payload[header_len + SURB_SIZE..].to_vec(), surb))
+}
+
+/// Send the SURB-ACK of a spooled message back into the mixnet,
+/// the sender recognizes it by the SURB's ID so the payload is empty.
+fn send_ack(cfg: &ProviderConfig, surb: Vec<u8>) {
+    match new_surb_reply(surb, &[]) {
+        Ok(ack) => {
+            if let Err(e) = cfg.outgoing_tx.send(ack) {
+                warn!("provider worker failed to send SURB-ACK: {}", e);
+            }
+        },
+        Err(e) => debug!("failed to build SURB-ACK: {}", e),
+    }
+}
+
+fn drop_packet(cfg: &ProviderConfig, packet: &Packet, reason: DropReason) {
+    cfg.metrics.drops().report(reason);
+    cfg.tracer.record(packet, TraceEvent::Dropped(reason));
+}
+
+fn on_packet(cfg: &ProviderConfig, packet: Packet) {
+    let recipient = match packet.recipient {
+        Some(ref x) => match normalize_recipient(x) {
+            Ok(x) => x,
+            Err(e) => {
+                debug!("Dropping user packet: {}", e);
+                drop_packet(cfg, &packet, DropReason::InvalidUserPacket);
+                return
+            },
+        },
+        None => {
+            debug!("Dropping user packet without recipient.");
+            drop_packet(cfg, &packet, DropReason::InvalidUserPacket);
+            return
+        },
+    };
+    let payload = match packet.payload {
+        Some(ref x) => x,
+        None => {
+            debug!("Dropping user packet without payload.");
+            drop_packet(cfg, &packet, DropReason::InvalidUserPacket);
+            return
+        },
+    };
+
+    let (message, surb) = if let Some(ref surb_reply) = packet.surb_reply {
+        let message = SpoolMessage {
+            surb_id: Some(surb_reply.id),
+            payload: payload.clone(),
+        };
+        (message, None)
+    } else {
+        let (message, surb) = match parse_forward_payload(payload) {
+            Ok(x) => x,
+            Err(e) => {
+                debug!("Dropping user packet: {}", e);
+                drop_packet(cfg, &packet, DropReason::InvalidUserPacket);
+                return
+            },
+        };
+        let message = SpoolMessage {
+            surb_id: None,
+            payload: message,
+        };
+        (message, surb)
+    };
+    if let Err(e) = cfg.spool.append(&recipient, &message) {
+        warn!("failed to spool message: {}", e);
+        drop_packet(cfg, &packet, DropReason::SpoolFailed);
+        return
+    }
+    // Unreliable packets are never acknowledged, even if
+    // they happen to carry a SURB.
+    if let Ok(PacketKind::ToUser) = packet.classify() {
+        match surb {
+            Some(surb) => send_ack(cfg, surb),
+            None => debug!("reliable user packet carries no SURB to acknowledge with"),
+        }
+    }
+}
+
+fn provider_worker(cfg: ProviderConfig) {
+    let mut sel = Select::new();
+    let oper1 = sel.recv(&cfg.provider_rx);
+    let oper2 = sel.recv(&cfg.halt_rx);
+    loop {
+        let oper = sel.select();
+        match oper.index() {
+            i if i == oper1 => {
+                match oper.recv(&cfg.provider_rx) {
+                    Ok(packet) => on_packet(&cfg, packet),
+                    Err(_) => {
+                        debug!("provider worker queue closed, halting.");
+                        break
+                    },
+                }
+            },
+            i if i == oper2 => {
+                // The halt channel is closed to signal shutdown.
+                let _ = oper.recv(&cfg.halt_rx);
+                // Spool the packets still queued by the crypto workers.
+                cfg.shutdown.drain("provider worker", &cfg.provider_rx, |packet| {
+                    on_packet(&cfg, packet);
+                    true
+                });
+                break
+            },
+            _ => unreachable!(),
+        }
+    }
+    if let Err(e) = cfg.spool.flush() {
+        warn!("failed to flush user spool: {}", e);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate ecdh_wrapper;
+    extern crate rand;
+    extern crate tempfile;
+
+    use self::ecdh_wrapper::PrivateKey;
+    use self::rand::os::OsRng;
+    use self::tempfile::TempDir;
+    use crossbeam_channel::unbounded;
+    use sphinxcrypto::client::{new_surb, PathHop};
+    use sphinxcrypto::commands::{Delay, Recipient, RoutingCommand, SURBReply};
+    use sphinxcrypto::constants::{FORWARD_PAYLOAD_SIZE, RECIPIENT_ID_SIZE, SURB_ID_SIZE};
+    use super::*;
+
+    fn user_packet(cmds: Vec<RoutingCommand>) -> Packet {
+        let mut rng = OsRng::new().unwrap();
+        let key = PrivateKey::generate(&mut rng).unwrap();
+        let hop = PathHop {
+            id: [1u8; 32],
+            public_key: key.public_key(),
+            commands: Some(vec![RoutingCommand::Recipient(Recipient { id: [2u8; RECIPIENT_ID_SIZE] }),
+                                RoutingCommand::SURBReply(SURBReply { id: [3u8; SURB_ID_SIZE] })]),
+        };
+        let (surb, _) = new_surb(&mut rng, vec![hop]).unwrap();
+        let header_len = constants::SPHINX_PLAINTEXT_HEADER_SIZE;
+        let mut payload = vec![0u8; FORWARD_PAYLOAD_SIZE];
+        payload[0] = constants::SURB_FLAG;
+        payload[header_len..header_len + SURB_SIZE].copy_from_slice(&surb);
+        let mut packet = Packet::default();
+        packet.set_commands(cmds);
+        packet.set_payload(Some(payload));
+        packet
+    }
+
+    #[test]
+    fn provider_surb_ack_test() {
+        let dir = TempDir::new().unwrap();
+        let (_, provider_rx) = unbounded();
+        let (outgoing_tx, outgoing_rx) = unbounded();
+        let (_, halt_rx) = unbounded();
+        let cfg = ProviderConfig {
+            provider_rx: provider_rx,
+            outgoing_tx: outgoing_tx,
+            halt_rx: halt_rx,
+            spool: UserSpool::new(dir.path().to_str().unwrap()).unwrap(),
+            shutdown: Shutdown::default(),
+            metrics: Metrics::new(),
+            tracer: Tracer::default(),
+        };
+        let mut id = [0u8; RECIPIENT_ID_SIZE];
+        id[..5].copy_from_slice(b"alice");
+        let recipient = RoutingCommand::Recipient(Recipient { id: id });
+
+        on_packet(&cfg, user_packet(vec![recipient.clone(), RoutingCommand::Delay(Delay { delay: 0 })]));
+        let ack = outgoing_rx.try_recv().unwrap();
+        assert_eq!(ack.next_hop.unwrap().id, [1u8; 32]);
+
+        // Unreliable packets are spooled but never acknowledged.
+        on_packet(&cfg, user_packet(vec![recipient.clone()]));
+        assert!(outgoing_rx.try_recv().is_err());
+        assert_eq!(cfg.spool.len(b"alice").unwrap(), 2);
+
+        // Packets which cannot be spooled are counted as dropped.
+        let mut garbled = user_packet(vec![recipient.clone()]);
+        garbled.payload.as_mut().unwrap()[1] = 1;
+        on_packet(&cfg, garbled);
+        on_packet(&cfg, user_packet(vec![RoutingCommand::Delay(Delay { delay: 0 })]));
+        assert_eq!(cfg.metrics.drops().count(DropReason::InvalidUserPacket), 2);
+        assert_eq!(cfg.spool.len(b"alice").unwrap(), 2);
+    }
+}
@@ -0,0 +1,254 @@
+// scheduler.rs - Mix strategy packet scheduler.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+extern crate crossbeam_channel;
+
+use std::cmp;
+use std::thread;
+use std::thread::JoinHandle;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crossbeam_channel::{Receiver, Sender, Select, RecvTimeoutError};
+
+use super::packet::Packet;
+use super::config::{Shutdown, drain_timed_out};
+use super::trace::{Tracer, TraceEvent};
+
+
+pub struct SchedulerConfig {
+    pub scheduler_rx: Receiver<Packet>,
+    pub outgoing_tx: Sender<Packet>,
+    pub halt_rx: Receiver<bool>,
+    pub shutdown: Shutdown,
+    pub tracer: Tracer,
+}
+
+pub fn start_scheduler(cfg: SchedulerConfig) -> JoinHandle<()> {
+    thread::spawn(move || {
+        scheduler(cfg)
+    })
+}
+
+/// A packet waiting in the scheduler queue along with the
+/// absolute time in milliseconds at which it must be dispatched.
+struct ScheduledPacket {
+    dispatch_at: u64,
+    packet: Packet,
+}
+
+impl PartialEq for ScheduledPacket {
+    fn eq(&self, other: &ScheduledPacket) -> bool {
+        self.dispatch_at == other.dispatch_at
+    }
+}
+
+impl Eq for ScheduledPacket {}
+
+impl PartialOrd for ScheduledPacket {
+    fn partial_cmp(&self, other: &ScheduledPacket) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ScheduledPacket {
+    // BinaryHeap is a max-heap so the ordering is reversed
+    // to make the earliest dispatch time pop first.
+    fn cmp(&self, other: &ScheduledPacket) -> Ordering {
+        other.dispatch_at.cmp(&self.dispatch_at)
+    }
+}
+
+fn now_millis() -> u64 {
+    let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(x) => x,
+        Err(_) => {
+            panic!("clock went back in time");
+        },
+    };
+    now.as_secs() * 1000 + now.subsec_nanos() as u64 / 1_000_000
+}
+
+/// Send every packet whose delay has elapsed to the outgoing
+/// dispatcher. Returns false if the dispatcher has gone away.
+fn dispatch_due(cfg: &SchedulerConfig, queue: &mut BinaryHeap<ScheduledPacket>, now: u64) -> bool {
+    while queue.peek().map_or(false, |x| x.dispatch_at <= now) {
+        let scheduled = queue.pop().unwrap();
+        cfg.tracer.record(&scheduled.packet, TraceEvent::Dispatched);
+        if let Err(e) = cfg.outgoing_tx.send(scheduled.packet) {
+            warn!("scheduler failed to dispatch packet: {}", e);
+            return false
+        }
+    }
+    true
+}
+
+fn schedule(cfg: &SchedulerConfig, queue: &mut BinaryHeap<ScheduledPacket>, packet: Packet) {
+    // The crypto worker has already subtracted the time spent
+    // in its queue from the delay, so counting from now
+    // dispatches the packet at receive_time plus the delay
+    // requested by the sender.
+    let dispatch_at = now_millis() + packet.delay;
+    debug!("scheduling packet {} for dispatch in {} ms", packet.id, packet.delay);
+    cfg.tracer.record(&packet, TraceEvent::Scheduled(packet.delay));
+    queue.push(ScheduledPacket {
+        dispatch_at: dispatch_at,
+        packet: packet,
+    });
+}
+
+/// Keep accepting packets from the crypto workers and dispatching
+/// them on schedule until both the input and the queue are empty,
+/// or the deadline passes.
+fn drain(cfg: &SchedulerConfig, queue: &mut BinaryHeap<ScheduledPacket>, deadline: Instant) {
+    let mut input_closed = false;
+    loop {
+        let now = now_millis();
+        if !dispatch_due(cfg, queue, now) {
+            return
+        }
+        if input_closed && queue.is_empty() {
+            return
+        }
+        let instant = Instant::now();
+        if instant >= deadline {
+            break
+        }
+        let mut wait = deadline - instant;
+        if let Some(next) = queue.peek() {
+            wait = cmp::min(wait, Duration::from_millis(next.dispatch_at - now));
+        }
+        if input_closed {
+            thread::sleep(wait);
+            continue
+        }
+        match cfg.scheduler_rx.recv_timeout(wait) {
+            Ok(packet) => schedule(cfg, queue, packet),
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => input_closed = true,
+        }
+    }
+    drain_timed_out("scheduler", queue.len() + cfg.scheduler_rx.len());
+}
+
+fn scheduler(cfg: SchedulerConfig) {
+    let mut queue: BinaryHeap<ScheduledPacket> = BinaryHeap::new();
+    let mut sel = Select::new();
+    let oper1 = sel.recv(&cfg.scheduler_rx);
+    let oper2 = sel.recv(&cfg.halt_rx);
+    loop {
+        // Dispatch every packet whose delay has elapsed.
+        let now = now_millis();
+        if !dispatch_due(&cfg, &mut queue, now) {
+            return
+        }
+
+        let oper = match queue.peek() {
+            Some(next) => {
+                match sel.select_timeout(Duration::from_millis(next.dispatch_at - now)) {
+                    Ok(x) => x,
+                    Err(_) => continue,
+                }
+            },
+            None => sel.select(),
+        };
+        match oper.index() {
+            i if i == oper1 => {
+                match oper.recv(&cfg.scheduler_rx) {
+                    Ok(packet) => schedule(&cfg, &mut queue, packet),
+                    Err(_) => {
+                        // The crypto workers have all exited.
+                        if let Some(deadline) = cfg.shutdown.drain_deadline() {
+                            drain(&cfg, &mut queue, deadline);
+                        }
+                        return
+                    },
+                }
+            },
+            i if i == oper2 => {
+                // The halt channel is closed to signal shutdown.
+                let _ = oper.recv(&cfg.halt_rx);
+                if let Some(deadline) = cfg.shutdown.drain_deadline() {
+                    drain(&cfg, &mut queue, deadline);
+                }
+                return
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crossbeam_channel::unbounded;
+
+    use super::*;
+
+    fn delayed_packet(id: u64, delay: u64) -> Packet {
+        let mut packet = Packet::default();
+        packet.id = id;
+        packet.delay = delay;
+        packet
+    }
+
+    #[test]
+    fn scheduler_delay_order_test() {
+        let (scheduler_tx, scheduler_rx) = unbounded();
+        let (outgoing_tx, outgoing_rx) = unbounded();
+        let (_halt_tx, halt_rx) = unbounded();
+        start_scheduler(SchedulerConfig {
+            scheduler_rx: scheduler_rx,
+            outgoing_tx: outgoing_tx,
+            halt_rx: halt_rx,
+            shutdown: Shutdown::default(),
+            tracer: Tracer::default(),
+        });
+
+        let start = Instant::now();
+        scheduler_tx.send(delayed_packet(1, 300)).unwrap();
+        scheduler_tx.send(delayed_packet(2, 50)).unwrap();
+
+        let first = outgoing_rx.recv().unwrap();
+        assert_eq!(first.id, 2);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        let second = outgoing_rx.recv().unwrap();
+        assert_eq!(second.id, 1);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[test]
+    fn scheduler_drain_on_halt_test() {
+        let (scheduler_tx, scheduler_rx) = unbounded();
+        let (outgoing_tx, outgoing_rx) = unbounded();
+        let (halt_tx, halt_rx) = unbounded::<bool>();
+        let handle = start_scheduler(SchedulerConfig {
+            scheduler_rx: scheduler_rx,
+            outgoing_tx: outgoing_tx,
+            halt_rx: halt_rx,
+            shutdown: Shutdown::default(),
+            tracer: Tracer::default(),
+        });
+
+        scheduler_tx.send(delayed_packet(1, 100)).unwrap();
+        drop(scheduler_tx);
+        drop(halt_tx);
+        handle.join().unwrap();
+        assert_eq!(outgoing_rx.try_recv().unwrap().id, 1);
+    }
+}
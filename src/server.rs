@@ -21,13 +21,14 @@ extern crate epoch;
 extern crate sphinx_replay_cache;
 
 use std::path::Path;
-use log4rs::encode::pattern::PatternEncoder;
-use log::LevelFilter;
-use crossbeam_channel::unbounded;
+use std::sync::Arc;
+use std::collections::HashMap;
+use std::net::TcpStream;
+use std::thread::JoinHandle;
+use crossbeam_channel::{unbounded, Sender};
 
 use ecdh_wrapper::PrivateKey;
 use epoch::Clock;
-use self::mix_link::messages::PeerAuthenticator;
 use sphinx_replay_cache::MixKeys;
 
 use super::constants;
@@ -35,44 +36,149 @@ use super::config::Config;
 use super::tcp_listener::TcpStreamFount;
 use super::wire_worker::{WireConfig, start_wire_worker,
                          PeerAuthenticatorBuilder,
-                         StaticAuthenticatorBuilder};
+                         PkiAuthenticatorBuilder,
+                         SessionTracker};
 use super::crypto_worker::{start_crypto_worker, CryptoWorkerConfig};
+use super::scheduler::{start_scheduler, SchedulerConfig};
+use super::outgoing::{start_outgoing_dispatcher, OutgoingConfig};
+use super::pki::{ConsensusStore, link_key_from_base64};
+use super::aqm::{self, Codel, AqmStats};
+use super::spool::UserSpool;
+use super::provider::{start_provider_worker, ProviderConfig};
+use super::pki_worker::{start_pki_worker, PkiWorkerConfig, PkiClient};
+use super::descriptor::DescriptorBuilder;
+use super::identity::load_or_generate_identity_key;
+use super::logging::init_logger;
+use super::metrics::{Metrics, MetricsConfig, start_metrics_worker};
+use super::drops::DropReason;
+use super::decoy::{self, start_decoy_worker, DecoyConfig, DecoyStats, PathStats};
+use super::kaetzchen::{start_kaetzchen_worker, KaetzchenConfig, KaetzchenRegistry, EchoService, ECHO_SERVICE};
+use super::plugin::{start_plugin_worker, PluginConfig};
+use super::trace::Tracer;
+use super::key_manager::{start_key_manager, KeyManagerConfig};
+use super::errors::ServerError;
 
 
-fn init_logger(log_dir: &str) {
-    use log4rs::config::{Appender, Root};
-    use log4rs::config::Config as Log4rsConfig;
-    use log4rs::append::file::FileAppender;
-    let log_path = Path::new(log_dir).join("mixnet_server.log");
-    let file_appender = FileAppender::builder()
-        .encoder(Box::new(PatternEncoder::new("{d} - {m}{n}")))
-        .build(log_path)
-        .unwrap();
-    let config = Log4rsConfig::builder()
-        .appender(Appender::builder().build("mixnet_server", Box::new(file_appender)))
-        .build(Root::builder().appender("mixnet_server").build(LevelFilter::Info)) // XXX
-        .unwrap();
-    let _handle = log4rs::init_config(config).unwrap();
+/// Start a listener, halting the ones already started
+/// if its address can not be bound.
+fn start_fount(founts: &mut Vec<TcpStreamFount>, address: String, tx: Sender<TcpStream>) -> Result<(), ServerError> {
+    let mut fount = TcpStreamFount::new(address.clone(), tx);
+    if let Err(e) = fount.run() {
+        error!("failed to listen on {}: {}", address, e);
+        for fount in founts.iter_mut() {
+            fount.halt();
+        }
+        founts.clear();
+        return Err(ServerError::ListenError(address, e))
+    }
+    founts.push(fount);
+    Ok(())
 }
 
 pub struct Server {
     cfg: Config,
     incoming_conn_founts: Vec<TcpStreamFount>,
-    peer_auth: PeerAuthenticator, // XXX
+    aqm: Option<Codel>,
+    halt_tx: Option<Sender<bool>>,
+    sessions: SessionTracker,
+    wire_workers: Vec<JoinHandle<()>>,
+    workers: Vec<JoinHandle<()>>,
+    mix_keys: Option<MixKeys>,
+    spool: Option<UserSpool>,
+    metrics: Metrics,
+    decoy_stats: DecoyStats,
+    kaetzchen: KaetzchenRegistry,
+    tracer: Tracer,
 }
 
 impl Server {
-    pub fn new(cfg: Config, peer_auth: PeerAuthenticator) -> Server {
-        let s = Server {
+    pub fn new(cfg: Config) -> Result<Server, ServerError> {
+        init_logger(&cfg.logging)?;
+        let tracer = match cfg.tracing {
+            Some(ref tracing) => {
+                warn!("packet tracing is enabled, do not use in production");
+                Tracer::new(tracing.sample_rate)
+            },
+            None => Tracer::default(),
+        };
+        Ok(Server {
             cfg: cfg,
             incoming_conn_founts: vec![],
-            peer_auth: peer_auth,
-        };
-        init_logger(s.cfg.logging.log_file.as_str());
-        s
+            aqm: None,
+            halt_tx: None,
+            sessions: SessionTracker::new(),
+            wire_workers: vec![],
+            workers: vec![],
+            mix_keys: None,
+            spool: None,
+            metrics: Metrics::new(),
+            decoy_stats: DecoyStats::new(),
+            kaetzchen: KaetzchenRegistry::new(),
+            tracer: tracer,
+        })
+    }
+
+    /// Returns the crypto worker queue AQM counters
+    /// or None if the server is not running.
+    pub fn aqm_stats(&self) -> Option<AqmStats> {
+        self.aqm.as_ref().map(|x| x.stats())
+    }
+
+    /// Returns a handle on the server's packet pipeline metrics.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Returns the number of packets dropped by the
+    /// crypto workers for each reason.
+    pub fn drop_counts(&self) -> Vec<(DropReason, u64)> {
+        self.metrics.drops().counts()
+    }
+
+    /// Returns the loss and latency of decoy traffic
+    /// keyed by the identity keys of the nodes along each path.
+    pub fn decoy_stats(&self) -> HashMap<Vec<[u8; 32]>, PathStats> {
+        self.decoy_stats.snapshot()
+    }
+
+    /// Returns the registry of services hosted by this provider,
+    /// services registered before `run` are served once it starts.
+    pub fn kaetzchen(&self) -> &KaetzchenRegistry {
+        &self.kaetzchen
     }
 
-    pub fn run(&mut self) {
+    /// Returns the packet tracer, which is disabled
+    /// unless the `[tracing]` section is present.
+    pub fn tracer(&self) -> &Tracer {
+        &self.tracer
+    }
+
+    /// Make the spool's client registry match the configured clients.
+    fn register_clients(&self, spool: &UserSpool) -> Result<(), ServerError> {
+        let mut configured = vec![];
+        for client in self.cfg.clients.iter() {
+            match link_key_from_base64(&client.link_public_key) {
+                Ok(key) => configured.push(key),
+                Err(e) => {
+                    error!("invalid link key for client {}: {}", client.name, e);
+                    return Err(ServerError::LinkKeyError(format!("client {}: {}", client.name, e)));
+                },
+            }
+        }
+        let registered = spool.clients().map_err(ServerError::SpoolError)?;
+        for key in registered.iter().filter(|x| !configured.contains(x)) {
+            spool.remove_client(key).map_err(ServerError::SpoolError)?;
+        }
+        for key in configured.iter().filter(|x| !registered.contains(x)) {
+            spool.add_client(key).map_err(ServerError::SpoolError)?;
+        }
+        info!("{} clients registered", configured.len());
+        Ok(())
+    }
+
+    /// Start all of the server's workers. Returns once
+    /// they are running, see `wait`.
+    pub fn run(&mut self) -> Result<(), ServerError> {
         info!("mix_server is still in pre-alpha. DO NOT DEPEND ON IT FOR STRONG SECURITY OR ANONYMITY.");
 
         let data_dir_path = Path::new(&self.cfg.server.data_dir);
@@ -84,58 +190,269 @@ impl Server {
             Ok(x) => x,
             Err(e) => {
                 error!("mix_server failed to load link keys: {}", e);
-                return;
+                return Err(ServerError::LinkKeyError(format!("{}", e)));
             },
         };
 
+        let identity_key = match load_or_generate_identity_key(&self.cfg.server.data_dir) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("mix_server failed to load identity key: {}", e);
+                return Err(ServerError::IdentityKeyError(e));
+            },
+        };
+        let identity = identity_key.public.to_bytes();
+
         let clock = Clock::new_katzenpost();
-        let mix_keys = match MixKeys::new(clock.clone(),
+        let mut mix_keys = match MixKeys::new(clock.clone(),
                                               constants::NUM_MIX_KEYS,
                                               self.cfg.server.data_dir.clone(),
                                               self.cfg.server.line_rate) {
             Ok(x) => x,
             Err(e) => {
                 error!("failed to load or generate mix keys: {}", e);
-                return;
+                return Err(ServerError::MixKeyError(format!("{}", e)));
             },
         };
+        // Our descriptor publishes the keys of the coming
+        // epochs, so they must exist before the PKI worker starts.
+        if let Err(e) = mix_keys.generate(clock.now().epoch) {
+            error!("failed to generate mix keys: {}", e);
+            return Err(ServerError::MixKeyError(format!("{}", e)));
+        }
+        let pki_client = match PkiClient::new(&self.cfg.pki, link_priv_key.clone(), identity) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("failed to create PKI client: {}", e);
+                return Err(ServerError::PkiError(e));
+            },
+        };
+        let spool = if self.cfg.server.is_provider {
+            match UserSpool::new(&self.cfg.server.data_dir) {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    error!("failed to open user spool: {}", e);
+                    return Err(ServerError::SpoolError(e));
+                },
+            }
+        } else {
+            None
+        };
+        if let Some(ref spool) = spool {
+            self.register_clients(spool)?;
+        }
+
         let (tcp_fount_tx, tcp_fount_rx) = unbounded();
+        let consensus = ConsensusStore::new();
         let (crypto_worker_tx, crypto_worker_rx) = unbounded();
-        let (pki_update_tx, pki_update_rx) = unbounded(); // XXX
-        let (halt_tx, halt_rx) = unbounded(); // XXX
+        let codel = match self.cfg.aqm {
+            Some(ref x) => Codel::new(x.target, x.interval),
+            None => Codel::new(aqm::DEFAULT_TARGET, aqm::DEFAULT_INTERVAL),
+        };
+        self.aqm = Some(codel.clone());
+        let (scheduler_tx, scheduler_rx) = unbounded();
+        let (outgoing_tx, outgoing_rx) = unbounded();
+        let (provider_tx, provider_rx) = unbounded();
+        let mut key_update_txs = vec![];
+        let (halt_tx, halt_rx) = unbounded();
+        self.halt_tx = Some(halt_tx);
+        self.mix_keys = Some(mix_keys.clone());
+        self.spool = spool.clone();
+        let shutdown = self.cfg.shutdown.clone().unwrap_or_default();
 
 
         for address in self.cfg.server.addresses.clone() {
-            let mut fount = TcpStreamFount::new(address, tcp_fount_tx.clone());
-            fount.run();
-            self.incoming_conn_founts.push(fount);
+            start_fount(&mut self.incoming_conn_founts, address, tcp_fount_tx.clone())?;
         }
+        if let Some(ref metrics_cfg) = self.cfg.metrics {
+            let (metrics_tx, metrics_rx) = unbounded();
+            start_fount(&mut self.incoming_conn_founts, metrics_cfg.address.clone(), metrics_tx)?;
+            self.workers.push(start_metrics_worker(MetricsConfig {
+                metrics: self.metrics.clone(),
+                stream_rx: metrics_rx,
+            }));
+        }
+        let peer_auth_builder = PeerAuthenticatorBuilder::Pki(PkiAuthenticatorBuilder {
+            spool: spool.clone(),
+            consensus: consensus.clone(),
+            clock: clock.clone(),
+        });
         for _ in 0..self.cfg.server.num_wire_workers {
-            let static_auth_builder = StaticAuthenticatorBuilder {
-                auth: self.peer_auth.clone(),
-            };
-            let builder = PeerAuthenticatorBuilder::Static(static_auth_builder);
-
             let wire_cfg = WireConfig {
                 link_private_key: link_priv_key.clone(),
+                identity: identity,
                 tcp_fount_rx: tcp_fount_rx.clone(),
                 crypto_worker_tx: crypto_worker_tx.clone(),
-                peer_auth_builder: builder,
+                peer_auth_builder: peer_auth_builder.clone(),
                 is_provider: self.cfg.server.is_provider,
+                spool: spool.clone(),
+                consensus: consensus.clone(),
+                clock: clock.clone(),
+                sessions: self.sessions.clone(),
+                metrics: self.metrics.clone(),
+                tracer: self.tracer.clone(),
             };
-            start_wire_worker(wire_cfg);
+            self.wire_workers.push(start_wire_worker(wire_cfg));
+        }
+        let (kaetzchen_tx, kaetzchen_rx) = unbounded();
+        if self.cfg.server.is_provider {
+            self.kaetzchen.register(ECHO_SERVICE, Arc::new(EchoService));
+            self.kaetzchen.register(decoy::LOOP_SERVICE, Arc::new(EchoService));
+            for plugin in self.cfg.plugins.iter() {
+                let (plugin_tx, plugin_rx) = unbounded();
+                self.kaetzchen.register_plugin(&plugin.name, plugin_tx);
+                self.workers.push(start_plugin_worker(PluginConfig {
+                    plugin: plugin.clone(),
+                    request_rx: plugin_rx,
+                    outgoing_tx: outgoing_tx.clone(),
+                    halt_rx: halt_rx.clone(),
+                }));
+            }
+            self.workers.push(start_kaetzchen_worker(KaetzchenConfig {
+                kaetzchen_rx: kaetzchen_rx,
+                outgoing_tx: outgoing_tx.clone(),
+                halt_rx: halt_rx.clone(),
+                registry: self.kaetzchen.clone(),
+                shutdown: shutdown.clone(),
+            }));
         }
+        let decoy_tx = match self.cfg.decoy {
+            Some(ref decoy) if !self.cfg.server.is_provider => {
+                let (decoy_tx, decoy_rx) = unbounded();
+                self.workers.push(start_decoy_worker(DecoyConfig {
+                    decoy_rx: decoy_rx,
+                    outgoing_tx: outgoing_tx.clone(),
+                    halt_rx: halt_rx.clone(),
+                    consensus: consensus.clone(),
+                    clock: clock.clone(),
+                    identity: identity,
+                    rate: decoy.rate,
+                    mean_delay: decoy.mean_delay,
+                    stats: self.decoy_stats.clone(),
+                    drops: self.metrics.drops().clone(),
+                }));
+                Some(decoy_tx)
+            },
+            _ => None,
+        };
         for _ in 0..self.cfg.server.num_crypto_workers {
+            let (key_update_tx, key_update_rx) = unbounded();
+            key_update_txs.push(key_update_tx);
             let cfg = CryptoWorkerConfig {
                 crypto_worker_rx: crypto_worker_rx.clone(),
-                update_rx: pki_update_rx.clone(),
+                scheduler_tx: scheduler_tx.clone(),
+                provider_tx: provider_tx.clone(),
+                update_rx: key_update_rx,
                 halt_rx: halt_rx.clone(),
                 slack_time: self.cfg.server.crypto_worker_slack_time,
+                aqm: codel.clone(),
                 clock: clock.clone(),
                 mix_keys: mix_keys.clone(),
                 is_provider: self.cfg.server.is_provider,
+                shutdown: shutdown.clone(),
+                metrics: self.metrics.clone(),
+                decoy_tx: decoy_tx.clone(),
+                kaetzchen: self.kaetzchen.clone(),
+                kaetzchen_tx: kaetzchen_tx.clone(),
+                tracer: self.tracer.clone(),
             };
-            start_crypto_worker(cfg);
+            self.workers.push(start_crypto_worker(cfg));
+        }
+        self.workers.push(start_scheduler(SchedulerConfig {
+            scheduler_rx: scheduler_rx,
+            outgoing_tx: outgoing_tx.clone(),
+            halt_rx: halt_rx.clone(),
+            shutdown: shutdown.clone(),
+            tracer: self.tracer.clone(),
+        }));
+        if let Some(ref spool) = spool {
+            self.workers.push(start_provider_worker(ProviderConfig {
+                provider_rx: provider_rx,
+                outgoing_tx: outgoing_tx.clone(),
+                halt_rx: halt_rx.clone(),
+                spool: spool.clone(),
+                shutdown: shutdown.clone(),
+                metrics: self.metrics.clone(),
+                tracer: self.tracer.clone(),
+            }));
+        }
+        self.workers.push(start_pki_worker(PkiWorkerConfig {
+            client: pki_client,
+            descriptor_builder: Some(DescriptorBuilder {
+                name: self.cfg.server.identifier.clone(),
+                addresses: self.cfg.server.addresses.clone(),
+                is_provider: self.cfg.server.is_provider,
+                link_key: link_priv_key.public_key(),
+                identity_key: identity_key,
+                mix_keys: mix_keys.clone(),
+            }),
+            consensus: consensus.clone(),
+            clock: clock.clone(),
+            peer_auth_builder: peer_auth_builder,
+            sessions: self.sessions.clone(),
+            halt_rx: halt_rx.clone(),
+        }));
+        self.workers.push(start_key_manager(KeyManagerConfig {
+            mix_keys: mix_keys.clone(),
+            clock: clock.clone(),
+            update_txs: key_update_txs,
+            halt_rx: halt_rx.clone(),
+        }));
+        self.workers.push(start_outgoing_dispatcher(OutgoingConfig {
+            link_private_key: link_priv_key.clone(),
+            identity: identity,
+            outgoing_rx: outgoing_rx,
+            halt_rx: halt_rx.clone(),
+            consensus: consensus.clone(),
+            clock: clock.clone(),
+            shutdown: shutdown,
+            metrics: self.metrics.clone(),
+            tracer: self.tracer.clone(),
+        }));
+        Ok(())
+    }
+
+    /// Block until all of the server's workers have exited.
+    pub fn wait(&mut self) {
+        for worker in self.wire_workers.drain(..).chain(self.workers.drain(..)) {
+            if let Err(e) = worker.join() {
+                error!("server worker panicked: {:?}", e);
+            }
+        }
+    }
+
+    /// Stop the server. New connections are refused, connected peers
+    /// are sent `Disconnect` and the remaining workers are halted,
+    /// draining or dropping their queued packets according to the
+    /// shutdown policy. Returns once everything has been flushed to disk.
+    pub fn shutdown(&mut self) {
+        info!("shutting down mix server");
+        for fount in self.incoming_conn_founts.iter_mut() {
+            fount.halt();
+        }
+        // Dropping the founts closes the wire workers' connection channel.
+        self.incoming_conn_founts.clear();
+        self.sessions.halt();
+        for worker in self.wire_workers.drain(..) {
+            if let Err(e) = worker.join() {
+                error!("wire worker panicked: {:?}", e);
+            }
+        }
+
+        // Closing the halt channel signals every other worker.
+        drop(self.halt_tx.take());
+        self.wait();
+
+        if let Some(ref mut mix_keys) = self.mix_keys {
+            if let Err(e) = mix_keys.flush() {
+                error!("failed to flush mix keys: {}", e);
+            }
+        }
+        if let Some(ref spool) = self.spool {
+            if let Err(e) = spool.flush() {
+                error!("failed to flush user spool: {}", e);
+            }
         }
+        info!("mix server shut down");
     }
 }
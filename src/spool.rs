@@ -0,0 +1,318 @@
+// spool.rs - Provider user message spool.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A persistent per-recipient message spool backed by sled.
+//!
+//! Messages for each recipient are stored under keys made of the
+//! recipient prefix followed by a big endian sequence number so
+//! that a prefix scan yields them in the order they were spooled.
+
+extern crate ecdh_wrapper;
+extern crate sled;
+extern crate sphinxcrypto;
+
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use byteorder::{BigEndian, ByteOrder};
+
+use ecdh_wrapper::PublicKey;
+use sled::Tree;
+use sphinxcrypto::commands::Recipient;
+use sphinxcrypto::constants::SURB_ID_SIZE;
+
+use super::pki::{public_key_from_bytes, public_key_to_bytes};
+use super::errors::SpoolError;
+
+
+const MESSAGE_PREFIX: u8 = b'm';
+const COUNTER_PREFIX: u8 = b'c';
+const CLIENT_PREFIX: u8 = b'k';
+
+const MESSAGE_KIND: u8 = 0;
+const SURB_REPLY_KIND: u8 = 1;
+
+fn db_error<E: fmt::Debug>(error: E) -> SpoolError {
+    SpoolError::DbError(format!("{:?}", error))
+}
+
+/// Normalize a raw recipient ID by stripping the NUL padding
+/// and lower casing it.
+pub fn normalize_recipient_id(id: &[u8]) -> Result<Vec<u8>, SpoolError> {
+    let end = id.iter().rposition(|x| *x != 0).map_or(0, |x| x + 1);
+    if end == 0 {
+        return Err(SpoolError::InvalidRecipient)
+    }
+    Ok(id[..end].to_ascii_lowercase())
+}
+
+pub fn normalize_recipient(recipient: &Recipient) -> Result<Vec<u8>, SpoolError> {
+    normalize_recipient_id(&recipient.id)
+}
+
+/// A spooled message, either a message payload or a SURB reply.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpoolMessage {
+    pub surb_id: Option<[u8; SURB_ID_SIZE]>,
+    pub payload: Vec<u8>,
+}
+
+impl SpoolMessage {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut raw = vec![];
+        match self.surb_id {
+            Some(ref id) => {
+                raw.push(SURB_REPLY_KIND);
+                raw.extend_from_slice(id);
+            },
+            None => raw.push(MESSAGE_KIND),
+        }
+        raw.extend_from_slice(&self.payload);
+        raw
+    }
+
+    fn from_bytes(raw: &[u8]) -> Result<SpoolMessage, SpoolError> {
+        if raw.is_empty() {
+            return Err(SpoolError::CorruptMessage)
+        }
+        match raw[0] {
+            MESSAGE_KIND => Ok(SpoolMessage {
+                surb_id: None,
+                payload: raw[1..].to_vec(),
+            }),
+            SURB_REPLY_KIND => {
+                if raw.len() < 1 + SURB_ID_SIZE {
+                    return Err(SpoolError::CorruptMessage)
+                }
+                let mut id = [0u8; SURB_ID_SIZE];
+                id.copy_from_slice(&raw[1..1 + SURB_ID_SIZE]);
+                Ok(SpoolMessage {
+                    surb_id: Some(id),
+                    payload: raw[1 + SURB_ID_SIZE..].to_vec(),
+                })
+            },
+            _ => Err(SpoolError::CorruptMessage),
+        }
+    }
+}
+
+/// UserSpool is a cheaply cloneable handle to the provider's
+/// on disk message spool.
+#[derive(Clone)]
+pub struct UserSpool {
+    tree: Arc<Tree>,
+    append_lock: Arc<Mutex<()>>,
+}
+
+impl UserSpool {
+    /// Open or create the spool in the given data directory.
+    pub fn new(data_dir: &str) -> Result<UserSpool, SpoolError> {
+        let path = Path::new(data_dir).join("user_spool");
+        let tree = Tree::start_default(path).map_err(db_error)?;
+        Ok(UserSpool {
+            tree: Arc::new(tree),
+            append_lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Returns true if both handles refer to the same spool.
+    pub fn same_spool(&self, other: &UserSpool) -> bool {
+        Arc::ptr_eq(&self.tree, &other.tree)
+    }
+
+    fn key(prefix: u8, recipient: &[u8]) -> Result<Vec<u8>, SpoolError> {
+        if recipient.is_empty() || recipient.len() > 255 {
+            return Err(SpoolError::InvalidRecipient)
+        }
+        let mut key = vec![prefix, recipient.len() as u8];
+        key.extend_from_slice(recipient);
+        Ok(key)
+    }
+
+    fn message_key(recipient: &[u8], sequence: u64) -> Result<Vec<u8>, SpoolError> {
+        let mut key = UserSpool::key(MESSAGE_PREFIX, recipient)?;
+        let mut raw_seq = [0u8; 8];
+        BigEndian::write_u64(&mut raw_seq, sequence);
+        key.extend_from_slice(&raw_seq);
+        Ok(key)
+    }
+
+    /// Append a message to the recipient's spool,
+    /// returning its sequence number.
+    pub fn append(&self, recipient: &[u8], message: &SpoolMessage) -> Result<u64, SpoolError> {
+        let _guard = self.append_lock.lock().unwrap();
+        let counter_key = UserSpool::key(COUNTER_PREFIX, recipient)?;
+        let sequence = match self.tree.get(&counter_key).map_err(db_error)? {
+            Some(x) => {
+                if x.len() != 8 {
+                    return Err(SpoolError::CorruptMessage)
+                }
+                BigEndian::read_u64(&x)
+            },
+            None => 0,
+        };
+        let mut raw_next = [0u8; 8];
+        BigEndian::write_u64(&mut raw_next, sequence + 1);
+        // The counter is persisted first so that a crash in between
+        // skips a sequence number rather than reusing one.
+        self.tree.set(counter_key, raw_next.to_vec()).map_err(db_error)?;
+        self.tree.flush().map_err(db_error)?;
+        self.tree.set(UserSpool::message_key(recipient, sequence)?, message.to_bytes()).map_err(db_error)?;
+        self.tree.flush().map_err(db_error)?;
+        Ok(sequence)
+    }
+
+    /// Returns the oldest message in the recipient's spool
+    /// along with its sequence number.
+    pub fn peek(&self, recipient: &[u8]) -> Result<Option<(u64, SpoolMessage)>, SpoolError> {
+        let prefix = UserSpool::key(MESSAGE_PREFIX, recipient)?;
+        if let Some(item) = self.tree.scan(&prefix).next() {
+            let (key, value) = item.map_err(db_error)?;
+            if !key.starts_with(&prefix) {
+                return Ok(None)
+            }
+            if key.len() != prefix.len() + 8 {
+                return Err(SpoolError::CorruptMessage)
+            }
+            let sequence = BigEndian::read_u64(&key[prefix.len()..]);
+            return Ok(Some((sequence, SpoolMessage::from_bytes(&value)?)))
+        }
+        Ok(None)
+    }
+
+    /// Delete the message with the given sequence number.
+    pub fn delete(&self, recipient: &[u8], sequence: u64) -> Result<(), SpoolError> {
+        self.tree.del(&UserSpool::message_key(recipient, sequence)?).map_err(db_error)?;
+        self.tree.flush().map_err(db_error)?;
+        Ok(())
+    }
+
+    /// Returns the number of messages spooled for the recipient.
+    pub fn len(&self, recipient: &[u8]) -> Result<usize, SpoolError> {
+        let prefix = UserSpool::key(MESSAGE_PREFIX, recipient)?;
+        let mut count = 0;
+        for item in self.tree.scan(&prefix) {
+            let (key, _) = item.map_err(db_error)?;
+            if !key.starts_with(&prefix) {
+                break
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn client_key(link_key: &PublicKey) -> Vec<u8> {
+        let mut key = vec![CLIENT_PREFIX];
+        key.extend_from_slice(&public_key_to_bytes(link_key));
+        key
+    }
+
+    /// Register a client, allowing it to connect with the given link key.
+    pub fn add_client(&self, link_key: &PublicKey) -> Result<(), SpoolError> {
+        self.tree.set(UserSpool::client_key(link_key), vec![]).map_err(db_error)?;
+        self.tree.flush().map_err(db_error)?;
+        Ok(())
+    }
+
+    pub fn remove_client(&self, link_key: &PublicKey) -> Result<(), SpoolError> {
+        self.tree.del(&UserSpool::client_key(link_key)).map_err(db_error)?;
+        self.tree.flush().map_err(db_error)?;
+        Ok(())
+    }
+
+    /// Returns the link keys of the registered clients.
+    pub fn clients(&self) -> Result<Vec<PublicKey>, SpoolError> {
+        let mut clients = vec![];
+        for item in self.tree.scan(&[CLIENT_PREFIX]) {
+            let (key, _) = item.map_err(db_error)?;
+            if key[0] != CLIENT_PREFIX {
+                break
+            }
+            clients.push(public_key_from_bytes(&key[1..]).map_err(|_| SpoolError::CorruptClientKey)?);
+        }
+        Ok(clients)
+    }
+
+    pub fn flush(&self) -> Result<(), SpoolError> {
+        self.tree.flush().map_err(db_error)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use super::*;
+
+    #[test]
+    fn spool_persistence_test() {
+        let dir = TempDir::new().unwrap();
+        let data_dir = dir.path().to_str().unwrap();
+        let alice = b"alice".to_vec();
+        let bob = b"bob".to_vec();
+        let message = SpoolMessage {
+            surb_id: None,
+            payload: b"hello".to_vec(),
+        };
+        let reply = SpoolMessage {
+            surb_id: Some([7u8; SURB_ID_SIZE]),
+            payload: b"reply".to_vec(),
+        };
+
+        {
+            let spool = UserSpool::new(data_dir).unwrap();
+            assert_eq!(spool.peek(&alice).unwrap(), None);
+            assert_eq!(spool.append(&alice, &message).unwrap(), 0);
+            assert_eq!(spool.append(&alice, &reply).unwrap(), 1);
+            assert_eq!(spool.append(&bob, &message).unwrap(), 0);
+            assert_eq!(spool.len(&alice).unwrap(), 2);
+        }
+
+        let spool = UserSpool::new(data_dir).unwrap();
+        assert_eq!(spool.peek(&alice).unwrap(), Some((0, message.clone())));
+        spool.delete(&alice, 0).unwrap();
+        assert_eq!(spool.peek(&alice).unwrap(), Some((1, reply)));
+        spool.delete(&alice, 1).unwrap();
+        assert_eq!(spool.peek(&alice).unwrap(), None);
+        assert_eq!(spool.len(&bob).unwrap(), 1);
+
+        // Sequence numbers are never reused.
+        assert_eq!(spool.append(&alice, &message).unwrap(), 2);
+    }
+
+    #[test]
+    fn spool_clients_test() {
+        let dir = TempDir::new().unwrap();
+        let spool = UserSpool::new(dir.path().to_str().unwrap()).unwrap();
+        let alice = public_key_from_bytes(&[1u8; 32]).unwrap();
+        let bob = public_key_from_bytes(&[2u8; 32]).unwrap();
+        spool.append(b"alice", &SpoolMessage {
+            surb_id: None,
+            payload: b"hello".to_vec(),
+        }).unwrap();
+        assert!(spool.clients().unwrap().is_empty());
+
+        spool.add_client(&alice).unwrap();
+        spool.add_client(&bob).unwrap();
+        spool.add_client(&alice).unwrap();
+        assert_eq!(spool.clients().unwrap(), vec![alice.clone(), bob.clone()]);
+        spool.remove_client(&alice).unwrap();
+        assert_eq!(spool.clients().unwrap(), vec![bob]);
+        assert_eq!(spool.len(b"alice").unwrap(), 1);
+    }
+}
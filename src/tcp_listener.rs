@@ -14,17 +14,27 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::io;
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::Duration;
 use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use crossbeam_channel::Sender;
 
 
+/// Milliseconds between checks for new connections, which
+/// bounds how long halting the accept loop takes.
+const ACCEPT_POLL_INTERVAL: u64 = 50;
+
+
 pub struct TcpStreamFount {
     listen_addr: String,
     stream_chan: Sender<TcpStream>,
     job_handle: Option<JoinHandle<()>>,
+    halted: Arc<AtomicBool>,
 }
 
 impl TcpStreamFount {
@@ -33,29 +43,71 @@ impl TcpStreamFount {
             listen_addr: listen_addr,
             stream_chan: chan,
             job_handle: None,
+            halted: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    pub fn run(&mut self) {
-        let listener = TcpListener::bind(self.listen_addr.clone()).unwrap();
+    /// Start accepting connections, fails if the
+    /// listen address can not be bound.
+    pub fn run(&mut self) -> Result<(), io::Error> {
+        let listener = TcpListener::bind(self.listen_addr.clone())?;
+        // The listener is polled so that the accept loop
+        // notices a halt without needing a connection.
+        listener.set_nonblocking(true)?;
         let ch = self.stream_chan.clone();
+        let halted = self.halted.clone();
         self.job_handle = Some(thread::spawn(move || {
-            for maybe_stream in listener.incoming() {
-                match maybe_stream {
-                    Ok(stream) => {
+            while !halted.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        if let Err(e) = stream.set_nonblocking(false) {
+                            warn!("failed to make accepted stream blocking: {}", e);
+                            continue
+                        }
                         if let Err(e) = ch.send(stream) {
                             warn!("send failure: {}", e);
                         }
-                    }
-                    Err(_) => {
-                        return;
-                    }
+                    },
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(ACCEPT_POLL_INTERVAL));
+                    },
+                    Err(e) => {
+                        warn!("failed to accept connection: {}", e);
+                        return
+                    },
                 }
             }
         }));
+        Ok(())
     }
 
+    /// Stop accepting connections and wait for the accept loop to
+    /// exit, which drops its sender of accepted streams.
     pub fn halt(&mut self) {
-        drop(self.job_handle.take());
+        let handle = match self.job_handle.take() {
+            Some(x) => x,
+            None => return,
+        };
+        self.halted.store(true, Ordering::SeqCst);
+        if let Err(e) = handle.join() {
+            warn!("listener on {} panicked: {:?}", self.listen_addr, e);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crossbeam_channel::unbounded;
+    use super::*;
+
+    #[test]
+    fn fount_halt_test() {
+        let (tx, rx) = unbounded();
+        let mut fount = TcpStreamFount::new("127.0.0.1:0".to_string(), tx);
+        fount.run().unwrap();
+        fount.halt();
+        drop(fount);
+        assert!(rx.recv().is_err());
     }
 }
@@ -0,0 +1,179 @@
+// trace.rs - Sampled packet lifecycle tracing.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Follows individual packets through the pipeline for debugging a
+//! local testnet. Traces reveal per packet timing, so tracing is off
+//! unless configured and then only a sample of the packets is traced.
+
+extern crate rand;
+
+use std::fmt;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::packet::Packet;
+use super::drops::DropReason;
+
+
+/// Log target of trace events, so they may be given their own level.
+pub const TRACE_TARGET: &str = "mix_server::trace";
+
+/// Number of trace records kept in memory.
+const MAX_TRACE_RECORDS: usize = 4096;
+
+/// A step in the life of a packet.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEvent {
+    /// Received from the peer with the given credentials.
+    Received(String),
+    /// Queued for a crypto worker.
+    Queued,
+    /// Unwrapped with the key of the given epoch.
+    Unwrapped(u64),
+    /// Classified as the given kind of packet.
+    Classified(&'static str),
+    /// Scheduled for dispatch after the given delay in milliseconds.
+    Scheduled(u64),
+    /// Handed to the outgoing dispatcher or a local worker.
+    Dispatched,
+    Dropped(DropReason),
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::TraceEvent::*;
+        match self {
+            Received(peer) => write!(f, "received from {}", peer),
+            Queued => write!(f, "queued"),
+            Unwrapped(epoch) => write!(f, "unwrapped with epoch {} key", epoch),
+            Classified(kind) => write!(f, "classified as {}", kind),
+            Scheduled(delay) => write!(f, "scheduled in {} ms", delay),
+            Dispatched => write!(f, "dispatched"),
+            Dropped(reason) => write!(f, "dropped ({})", reason),
+        }
+    }
+}
+
+/// A trace event along with the packet it happened to and
+/// the time in milliseconds since the unix epoch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    pub packet_id: u64,
+    pub time: u64,
+    pub event: TraceEvent,
+}
+
+struct TraceState {
+    sample_rate: f64,
+    records: Mutex<VecDeque<TraceRecord>>,
+}
+
+/// Tracer records the lifecycle of sampled packets. The
+/// default tracer is disabled and records nothing.
+#[derive(Clone, Default)]
+pub struct Tracer {
+    state: Option<Arc<TraceState>>,
+}
+
+fn now_millis() -> u64 {
+    let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(x) => x,
+        Err(_) => {
+            panic!("clock went back in time");
+        },
+    };
+    now.as_secs() * 1000 + now.subsec_nanos() as u64 / 1_000_000
+}
+
+impl Tracer {
+    /// Returns a tracer following the given fraction of packets.
+    pub fn new(sample_rate: f64) -> Tracer {
+        Tracer {
+            state: Some(Arc::new(TraceState {
+                sample_rate: sample_rate,
+                records: Mutex::new(VecDeque::new()),
+            })),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.state.is_some()
+    }
+
+    /// Decide whether a newly received packet is
+    /// traced, and if so record its arrival.
+    pub fn received(&self, packet: &mut Packet, peer: &str) {
+        if let Some(ref state) = self.state {
+            packet.traced = rand::random::<f64>() < state.sample_rate;
+            self.record(packet, TraceEvent::Received(peer.to_string()));
+        }
+    }
+
+    pub fn record(&self, packet: &Packet, event: TraceEvent) {
+        let state = match self.state {
+            Some(ref x) if packet.traced => x,
+            _ => return,
+        };
+        info!(target: TRACE_TARGET, "packet {}: {}", packet.id, event);
+        let mut records = state.records.lock().unwrap();
+        if records.len() == MAX_TRACE_RECORDS {
+            records.pop_front();
+        }
+        records.push_back(TraceRecord {
+            packet_id: packet.id,
+            time: now_millis(),
+            event: event,
+        });
+    }
+
+    /// Returns the recorded events of a packet, oldest first.
+    pub fn events(&self, packet_id: u64) -> Vec<TraceRecord> {
+        match self.state {
+            Some(ref state) => state.records.lock().unwrap().iter()
+                .filter(|x| x.packet_id == packet_id)
+                .cloned()
+                .collect(),
+            None => vec![],
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracer_sampling_test() {
+        let disabled = Tracer::default();
+        let mut packet = Packet::default();
+        packet.id = 1;
+        disabled.received(&mut packet, "peer");
+        assert!(!packet.traced);
+        assert!(disabled.events(1).is_empty());
+
+        let tracer = Tracer::new(1.0);
+        tracer.received(&mut packet, "peer");
+        tracer.record(&packet, TraceEvent::Scheduled(5));
+        let mut other = Packet::default();
+        other.id = 2;
+        tracer.record(&other, TraceEvent::Queued);
+        let events: Vec<TraceEvent> = tracer.events(1).into_iter().map(|x| x.event).collect();
+        assert_eq!(events, vec![TraceEvent::Received("peer".to_string()), TraceEvent::Scheduled(5)]);
+        assert!(tracer.events(2).is_empty());
+    }
+}
@@ -19,51 +19,250 @@ extern crate crossbeam_utils;
 extern crate crossbeam_channel;
 extern crate crossbeam_thread;
 extern crate ecdh_wrapper;
+extern crate epoch;
 extern crate mix_link;
+extern crate rustc_serialize;
 
-use std::sync::{Arc, Barrier};
-use std::net::TcpStream;
+use std::fmt;
+use std::sync::{Arc, Barrier, Mutex};
+use std::net::{Shutdown, TcpStream};
 use std::thread as std_thread;
+use std::thread::JoinHandle;
+use std::collections::HashMap;
 
 use crossbeam_utils::thread;
 use crossbeam_channel::{Receiver, Sender, unbounded};
+use rustc_serialize::hex::ToHex;
 
 use ecdh_wrapper::PrivateKey;
+use epoch::Clock;
 use mix_link::sync::Session;
 use mix_link::errors::HandshakeError;
-use mix_link::messages::{SessionConfig, PeerAuthenticator};
+use mix_link::messages::{SessionConfig, PeerAuthenticator, PeerCredentials,
+                          ServerAuthenticatorState, ProviderAuthenticatorState};
 use mix_link::commands::Command;
 
 use packet::Packet;
+use spool::{UserSpool, normalize_recipient_id};
+use pki::{ConsensusStore, ConsensusStatus};
+use errors::RetrieveMessageError;
+use metrics::Metrics;
+use trace::{Tracer, TraceEvent};
 
 #[derive(PartialEq, Debug, Clone)]
 pub struct StaticAuthenticatorBuilder {
     pub auth: PeerAuthenticator,
 }
 
+/// PkiAuthenticatorBuilder builds authenticators which accept the
+/// mixes and providers listed in the current consensus document.
+/// Providers also accept the clients registered in their spool,
+/// unless the client's key is published as a node's link key.
+#[derive(Clone)]
+pub struct PkiAuthenticatorBuilder {
+    pub spool: Option<UserSpool>,
+    pub consensus: ConsensusStore,
+    pub clock: Clock,
+}
+
+impl PkiAuthenticatorBuilder {
+    fn build(&self) -> PeerAuthenticator {
+        let mut mix_map = HashMap::new();
+        let epoch = self.clock.now().epoch;
+        match self.consensus.get(epoch) {
+            Some(doc) => {
+                for node in doc.nodes() {
+                    match node.link_public_key() {
+                        Ok(key) => {
+                            mix_map.insert(key, true);
+                        },
+                        Err(e) => warn!("invalid link key for {} in consensus: {}", node.name, e),
+                    }
+                }
+            },
+            None => debug!("no consensus for epoch {}, rejecting all mixes", epoch),
+        }
+        let spool = match self.spool {
+            Some(ref x) => x,
+            None => {
+                return PeerAuthenticator::Server(ServerAuthenticatorState {
+                    mix_map: mix_map,
+                })
+            },
+        };
+        let mut client_map = HashMap::new();
+        match spool.clients() {
+            Ok(clients) => {
+                for key in clients.into_iter().filter(|x| !mix_map.contains_key(x)) {
+                    client_map.insert(key, true);
+                }
+            },
+            Err(e) => warn!("failed to load clients, rejecting all clients: {}", e),
+        }
+        PeerAuthenticator::Provider(ProviderAuthenticatorState {
+            client_map: client_map,
+            mix_map: mix_map,
+        })
+    }
+}
+
+impl fmt::Debug for PkiAuthenticatorBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PkiAuthenticatorBuilder {{ is_provider: {} }}", self.spool.is_some())
+    }
+}
+
+/// Builders are equal when they authenticate against
+/// the same consensus store and spool.
+impl PartialEq for PkiAuthenticatorBuilder {
+    fn eq(&self, other: &PkiAuthenticatorBuilder) -> bool {
+        let same_spool = match (&self.spool, &other.spool) {
+            (Some(x), Some(y)) => x.same_spool(y),
+            (None, None) => true,
+            _ => false,
+        };
+        same_spool && self.consensus.same_store(&other.consensus)
+    }
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub enum PeerAuthenticatorBuilder {
     Static(StaticAuthenticatorBuilder),
+    Pki(PkiAuthenticatorBuilder),
 }
 
 impl PeerAuthenticatorBuilder {
-    fn build(&self) -> PeerAuthenticator {
+    pub fn build(&self) -> PeerAuthenticator {
         match *self {
             PeerAuthenticatorBuilder::Static(ref builder) => {
                 builder.auth.clone()
             },
+            PeerAuthenticatorBuilder::Pki(ref builder) => {
+                builder.build()
+            },
         }
     }
 }
 
 
+struct TrackedSession {
+    stream: TcpStream,
+    peer: Option<PeerCredentials>,
+}
+
+#[derive(Default)]
+struct TrackerState {
+    halted: bool,
+    next_id: u64,
+    sessions: HashMap<u64, TrackedSession>,
+}
+
+/// SessionTracker keeps a handle on the socket of every live
+/// session so that readers blocked on it can be interrupted
+/// when the server shuts down or the peer loses its authorization.
+#[derive(Clone, Default)]
+pub struct SessionTracker {
+    state: Arc<Mutex<TrackerState>>,
+}
+
+impl SessionTracker {
+    pub fn new() -> SessionTracker {
+        SessionTracker::default()
+    }
+
+    /// Track a new connection, returns None if the
+    /// server is shutting down.
+    fn register(&self, stream: &TcpStream) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        if state.halted {
+            return None
+        }
+        let clone = match stream.try_clone() {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("failed to clone session stream: {}", e);
+                return None
+            },
+        };
+        let id = state.next_id;
+        state.next_id += 1;
+        state.sessions.insert(id, TrackedSession {
+            stream: clone,
+            peer: None,
+        });
+        Some(id)
+    }
+
+    /// Record the credentials the peer authenticated with.
+    fn authenticated(&self, id: u64, peer: &PeerCredentials) {
+        if let Some(session) = self.state.lock().unwrap().sessions.get_mut(&id) {
+            session.peer = Some(peer.clone());
+        }
+    }
+
+    fn unregister(&self, id: u64) {
+        self.state.lock().unwrap().sessions.remove(&id);
+    }
+
+    fn is_halted(&self) -> bool {
+        self.state.lock().unwrap().halted
+    }
+
+    /// Close the sessions of peers the given authenticator
+    /// no longer accepts, returns the number of sessions closed.
+    pub fn close_unauthorized(&self, auth: &PeerAuthenticator) -> usize {
+        let state = self.state.lock().unwrap();
+        let mut closed = 0;
+        for session in state.sessions.values() {
+            let peer = match session.peer {
+                Some(ref x) => x,
+                None => continue,
+            };
+            if auth.is_peer_valid(peer) {
+                continue
+            }
+            if let Err(e) = session.stream.shutdown(Shutdown::Both) {
+                debug!("failed to shut down session stream: {}", e);
+            }
+            closed += 1;
+        }
+        closed
+    }
+
+    /// Stop reading from every session. Readers notice the
+    /// shutdown, send `Disconnect` to their peer and close.
+    pub fn halt(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.halted = true;
+        for session in state.sessions.values() {
+            if let Err(e) = session.stream.shutdown(Shutdown::Read) {
+                debug!("failed to shut down session stream: {}", e);
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct WireConfig {
     pub link_private_key: PrivateKey,
+    pub identity: [u8; 32],
     pub tcp_fount_rx: Receiver<TcpStream>,
     pub crypto_worker_tx: Sender<Packet>,
     pub peer_auth_builder: PeerAuthenticatorBuilder,
     pub is_provider: bool,
+    pub spool: Option<UserSpool>,
+    pub consensus: ConsensusStore,
+    pub clock: Clock,
+    pub sessions: SessionTracker,
+    pub metrics: Metrics,
+    pub tracer: Tracer,
+}
+
+/// Per session state of a client's message retrieval.
+#[derive(Default)]
+struct RetrievalState {
+    sequence: u32,
+    delivered: Option<u64>,
 }
 
 fn create_session(session_config: SessionConfig, stream: TcpStream) -> Result<Session, HandshakeError> {
@@ -74,113 +273,186 @@ fn create_session(session_config: SessionConfig, stream: TcpStream) -> Result<Se
     Ok(session)
 }
 
-fn session_dispatcher(reader_tx: Sender<Session>, barrier: Arc<Barrier>, cfg: WireConfig) {
+fn command_name(cmd: &Command) -> &'static str {
+    match cmd {
+        Command::NoOp{} => "NoOp",
+        Command::Disconnect{} => "Disconnect",
+        Command::SendPacket{..} => "SendPacket",
+        Command::RetrieveMessage{..} => "RetrieveMessage",
+        Command::Message{..} => "Message",
+        Command::MessageACK{..} => "MessageACK",
+        Command::MessageEmpty{..} => "MessageEmpty",
+        Command::GetConsensus{..} => "GetConsensus",
+        Command::Consensus{..} => "Consensus",
+        Command::PostDescriptor{..} => "PostDescriptor",
+        Command::PostDescriptorStatus{..} => "PostDescriptorStatus",
+    }
+}
+
+fn session_dispatcher(reader_tx: Sender<(Session, u64)>, barrier: Arc<Barrier>, cfg: WireConfig) {
     loop {
-        barrier.wait();
         if let Ok(stream) = cfg.tcp_fount_rx.recv() {
+            let id = match cfg.sessions.register(&stream) {
+                Some(x) => x,
+                None => {
+                    debug!("rejecting connection, wire worker is shutting down.");
+                    continue
+                },
+            };
             let session_config = SessionConfig{
                 authenticator: cfg.peer_auth_builder.build(),
                 authentication_key: cfg.link_private_key.clone(),
                 peer_public_key: None,
-                additional_data: vec![],
+                additional_data: cfg.identity.to_vec(),
             };
             let session = match create_session(session_config, stream) {
                 Ok(x) => x,
                 Err(e) => {
                     warn!("failed to create noise session: {}", e);
+                    cfg.metrics.handshake_failed();
+                    cfg.sessions.unregister(id);
                     continue
                 },
             };
-            if let Err(e) = reader_tx.send(session) {
+            cfg.metrics.session_accepted();
+            cfg.sessions.authenticated(id, session.peer_credentials());
+            if let Err(e) = reader_tx.send((session, id)) {
                 warn!("shutting down wire worker because of a failure to dispatch session to reader thread: {}", e);
                 return
             }
+            // Wait for the reader to be done with the session.
+            barrier.wait();
         } else {
-            warn!("fount chan recv failure, halting wire worker.");
+            debug!("fount chan closed, halting wire worker.");
             return
         }
     } // end of loop {
 }
 
-fn reader(reader_rx: Receiver<Session>, crypto_worker_tx: Sender<Packet>, barrier: Arc<Barrier>, is_provider: bool) {
+fn reader(reader_rx: Receiver<(Session, u64)>, cfg: WireConfig, barrier: Arc<Barrier>) {
     loop {
-        barrier.wait();
-        let mut session = match reader_rx.recv() {
+        let (mut session, id) = match reader_rx.recv() {
             Ok(x) => x,
-            Err(e) => {
-                warn!("failed to recv session on reader_rx: {}", e);
+            Err(_) => {
+                debug!("session dispatcher has exited, halting reader.");
                 return
             },
         };
+        read_session(&mut session, &cfg);
+        cfg.sessions.unregister(id);
+        barrier.wait();
+    }
+}
 
-        loop {
-            let cmd = match session.recv_command() {
-                Ok(x) => x,
-                Err(_) => {
-                    session.close();
-                    break
-                },
-            };
-            debug!("server received command {:?}", cmd);
-
-            if session.from_client() {
-                match &cmd {
-                    Command::RetrieveMessage {
-                        sequence,
-                    } => {
-                        debug!("Received RetrieveMessage from peer.");
-                        on_retrieve_message(&cmd);
-                        continue
-                    },
-                    Command::GetConsensus {
-                        epoch,
-                    } => {
-                        debug!("Received GetConsensus from peer.");
-                        on_get_consensus(&cmd);
-                        continue
-                    },
-                    _ => {},
+fn read_session(session: &mut Session, cfg: &WireConfig) {
+    let mut retrieval = RetrievalState::default();
+
+    loop {
+        let cmd = match session.recv_command() {
+            Ok(x) => x,
+            Err(_) => {
+                if cfg.sessions.is_halted() {
+                    if let Err(e) = session.send_command(&Command::Disconnect{}) {
+                        debug!("failed to send Disconnect to peer: {}", e);
+                    }
                 }
-            }
+                session.close();
+                return
+            },
+        };
+        debug!("server received command {:?}", cmd);
+        cfg.metrics.command_received(command_name(&cmd));
 
+        if session.from_client() {
             match &cmd {
-                Command::NoOp{} => {
-                    debug!("NoOp received!");
-                },
-                Command::SendPacket {
-                    sphinx_packet
+                Command::RetrieveMessage {
+                    sequence,
                 } => {
-                    let mut packet = match Packet::new(sphinx_packet) {
+                    debug!("Received RetrieveMessage from peer.");
+                    let spool = match cfg.spool {
+                        Some(ref x) => x,
+                        None => {
+                            debug!("Ignoring RetrieveMessage: (not a provider)");
+                            continue
+                        },
+                    };
+                    let response = match on_retrieve_message(spool, &session.peer_credentials().additional_data,
+                                                             &mut retrieval, *sequence) {
                         Ok(x) => x,
                         Err(e) => {
-                            warn!("invalid sphinx packet: {}", e);
-                            continue
+                            warn!("failed to handle RetrieveMessage: {}", e);
+                            session.close();
+                            return
                         },
                     };
-                    packet.must_forward = session.from_client();
-                    packet.must_terminate = is_provider && !session.from_client();
-                    // XXX fixme: use select statement instead of single channel usage
-                    if let Err(e) = crypto_worker_tx.send(packet) {
-                        warn!("failed to send to crypto worker channel: {}", e);
+                    if let Err(e) = session.send_command(&response) {
+                        warn!("failed to send RetrieveMessage response: {}", e);
+                        session.close();
                         return
                     }
+                    continue
                 },
-                Command::Disconnect{} => {
-
-                },
-                _ => {
-                    debug!("received unhandled command");
+                Command::GetConsensus {
+                    epoch,
+                } => {
+                    debug!("Received GetConsensus from peer.");
+                    let response = on_get_consensus(&cfg.consensus, &cfg.clock, *epoch);
+                    if let Err(e) = session.send_command(&response) {
+                        warn!("failed to send Consensus response: {}", e);
+                        session.close();
+                        return
+                    }
                     continue
-                }
-            } // match cmd {
+                },
+                _ => {},
+            }
         }
+
+        match &cmd {
+            Command::NoOp{} => {
+                debug!("NoOp received!");
+            },
+            Command::SendPacket {
+                sphinx_packet
+            } => {
+                let mut packet = match Packet::new(sphinx_packet) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        warn!("invalid sphinx packet: {}", e);
+                        continue
+                    },
+                };
+                packet.must_forward = session.from_client();
+                packet.must_terminate = cfg.is_provider && !session.from_client();
+                if cfg.tracer.is_enabled() {
+                    let peer = session.peer_credentials().additional_data.to_hex();
+                    cfg.tracer.received(&mut packet, &peer);
+                    cfg.tracer.record(&packet, TraceEvent::Queued);
+                }
+                // XXX fixme: use select statement instead of single channel usage
+                if let Err(e) = cfg.crypto_worker_tx.send(packet) {
+                    warn!("failed to send to crypto worker channel: {}", e);
+                    session.close();
+                    return
+                }
+            },
+            Command::Disconnect{} => {
+                debug!("peer disconnected.");
+                session.close();
+                return
+            },
+            _ => {
+                debug!("received unhandled command");
+                continue
+            }
+        } // match cmd {
     }
 }
 
-pub fn start_wire_worker(cfg: WireConfig) {
+pub fn start_wire_worker(cfg: WireConfig) -> JoinHandle<()> {
     std_thread::spawn(move || {
         start_wire_worker_runner(cfg);
-    });
+    })
 }
 
 pub fn start_wire_worker_runner(cfg: WireConfig) {
@@ -188,8 +460,7 @@ pub fn start_wire_worker_runner(cfg: WireConfig) {
     let (reader_tx, reader_rx) = unbounded();
     let dispatcher_barrier = barrier.clone();
     let reader_barrier = barrier.clone();
-    let crypto_worker_tx = cfg.crypto_worker_tx.clone();
-    let is_provider = cfg.is_provider;
+    let reader_cfg = cfg.clone();
 
     if let Err(_) = thread::scope(|scope| {
         let mut thread_handles = vec![];
@@ -197,7 +468,7 @@ pub fn start_wire_worker_runner(cfg: WireConfig) {
             session_dispatcher(reader_tx, dispatcher_barrier, cfg);
         })));
         thread_handles.push(Some(scope.spawn(move |_| {
-            reader(reader_rx, crypto_worker_tx, reader_barrier, is_provider);
+            reader(reader_rx, reader_cfg, reader_barrier);
         })));
     }) {
         warn!("wire worker failed to spawn thread(s)");
@@ -205,12 +476,60 @@ pub fn start_wire_worker_runner(cfg: WireConfig) {
     }
 }
 
-fn on_retrieve_message(cmd: &Command) {
+/// Implements the provider side of message retrieval. A sequence
+/// number one past the last one seen acknowledges the previously
+/// delivered message, which is then removed from the spool.
+fn on_retrieve_message(spool: &UserSpool, peer_id: &[u8], state: &mut RetrievalState, sequence: u32) -> Result<Command, RetrieveMessageError> {
+    let recipient = normalize_recipient_id(peer_id)?;
+    if sequence == state.sequence.wrapping_add(1) {
+        if let Some(delivered) = state.delivered.take() {
+            spool.delete(&recipient, delivered)?;
+        }
+        state.sequence = sequence;
+    } else if sequence != state.sequence {
+        return Err(RetrieveMessageError::OutOfSequence)
+    }
 
+    let (spool_sequence, message) = match spool.peek(&recipient)? {
+        Some(x) => x,
+        None => {
+            return Ok(Command::MessageEmpty {
+                sequence: sequence,
+            })
+        },
+    };
+    state.delivered = Some(spool_sequence);
+    let remaining = spool.len(&recipient)?.saturating_sub(1);
+    let queue_size_hint = if remaining > 255 { 255 } else { remaining as u8 };
+    match message.surb_id {
+        Some(id) => Ok(Command::MessageACK {
+            queue_size_hint: queue_size_hint,
+            sequence: sequence,
+            id: id,
+            payload: message.payload,
+        }),
+        None => Ok(Command::Message {
+            queue_size_hint: queue_size_hint,
+            sequence: sequence,
+            payload: message.payload,
+        }),
+    }
 }
 
-fn on_get_consensus(cmd: &Command) {
-
+fn on_get_consensus(consensus: &ConsensusStore, clock: &Clock, epoch: u64) -> Command {
+    match consensus.get_signed(epoch, clock.now().epoch) {
+        Ok(raw) => Command::Consensus {
+            error_code: ConsensusStatus::Ok.error_code(),
+            payload: raw.to_vec(),
+        },
+        Err(status) => {
+            debug!("GetConsensus for epoch {} failed: {:?}", epoch, status);
+            Command::Consensus {
+                error_code: status.error_code(),
+                payload: vec![],
+            }
+        },
+    }
 }
 
 
@@ -219,6 +538,7 @@ mod tests {
     extern crate rand;
     extern crate ecdh_wrapper;
     extern crate mix_link;
+    extern crate tempfile;
 
     use std::thread;
     use std::time::Duration;
@@ -228,10 +548,143 @@ mod tests {
     use self::rand::os::OsRng;
     use ecdh_wrapper::{PrivateKey, PublicKey};
     use mix_link::messages::{SessionConfig, PeerAuthenticator, ServerAuthenticatorState};
+    use epoch::Clock;
+    use pki::{ConsensusStore, Document, MixDescriptor, public_key_to_bytes};
+    use spool::SpoolMessage;
+    use sphinxcrypto::constants::SURB_ID_SIZE;
+    use self::tempfile::TempDir;
 
     use super::super::wire_worker::{start_wire_worker};
     use super::*;
 
+    #[test]
+    fn pki_authenticator_test() {
+        let mut rng = OsRng::new().unwrap();
+        let mix_key = PrivateKey::generate(&mut rng).unwrap().public_key();
+        let client_key = PrivateKey::generate(&mut rng).unwrap().public_key();
+        let clock = Clock::new_katzenpost();
+        let consensus = ConsensusStore::new();
+        let epoch = clock.now().epoch;
+        for epoch in vec![epoch, epoch + 1] {
+            consensus.insert(Document {
+                epoch: epoch,
+                topology: vec![vec![MixDescriptor {
+                    name: "mix1".to_string(),
+                    identity_key: [1u8; 32],
+                    link_key: public_key_to_bytes(&mix_key),
+                    addresses: vec!["127.0.0.1:29483".to_string()],
+                    is_provider: false,
+                    layer: 0,
+                    mix_keys: HashMap::new(),
+                }]],
+                providers: vec![],
+            }, vec![]);
+        }
+        let dir = TempDir::new().unwrap();
+        let spool = UserSpool::new(dir.path().to_str().unwrap()).unwrap();
+        spool.add_client(&client_key).unwrap();
+        // A node's link key never authenticates it as a client.
+        spool.add_client(&mix_key).unwrap();
+
+        let mix_builder = PkiAuthenticatorBuilder {
+            spool: None,
+            consensus: consensus.clone(),
+            clock: clock.clone(),
+        };
+        match mix_builder.build() {
+            PeerAuthenticator::Server(state) => {
+                assert_eq!(state.mix_map.len(), 1);
+                assert!(state.mix_map.contains_key(&mix_key));
+            },
+            _ => panic!("expected a server authenticator"),
+        }
+
+        let provider_builder = PkiAuthenticatorBuilder {
+            spool: Some(spool.clone()),
+            consensus: consensus.clone(),
+            clock: clock.clone(),
+        };
+        match provider_builder.build() {
+            PeerAuthenticator::Provider(state) => {
+                assert_eq!(state.mix_map.len(), 1);
+                assert!(state.mix_map.contains_key(&mix_key));
+                assert_eq!(state.client_map.len(), 1);
+                assert!(state.client_map.contains_key(&client_key));
+            },
+            _ => panic!("expected a provider authenticator"),
+        }
+
+        // Without a consensus every mix is rejected.
+        let unknown_builder = PkiAuthenticatorBuilder {
+            spool: None,
+            consensus: ConsensusStore::new(),
+            clock: clock,
+        };
+        match unknown_builder.build() {
+            PeerAuthenticator::Server(state) => assert!(state.mix_map.is_empty()),
+            _ => panic!("expected a server authenticator"),
+        }
+
+        assert_eq!(PeerAuthenticatorBuilder::Pki(provider_builder.clone()),
+                   PeerAuthenticatorBuilder::Pki(provider_builder));
+        assert!(mix_builder != unknown_builder);
+    }
+
+    #[test]
+    fn retrieve_message_test() {
+        let dir = TempDir::new().unwrap();
+        let spool = UserSpool::new(dir.path().to_str().unwrap()).unwrap();
+        let peer_id = b"Alice\x00\x00\x00";
+        let recipient = normalize_recipient_id(peer_id).unwrap();
+        spool.append(&recipient, &SpoolMessage {
+            surb_id: None,
+            payload: b"first".to_vec(),
+        }).unwrap();
+        spool.append(&recipient, &SpoolMessage {
+            surb_id: Some([3u8; SURB_ID_SIZE]),
+            payload: b"second".to_vec(),
+        }).unwrap();
+        let mut state = RetrievalState::default();
+
+        // The first message is delivered, and delivered
+        // again if the client repeats the sequence number.
+        for _ in 0..2 {
+            match on_retrieve_message(&spool, peer_id, &mut state, 0).unwrap() {
+                Command::Message { queue_size_hint, sequence, payload } => {
+                    assert_eq!(queue_size_hint, 1);
+                    assert_eq!(sequence, 0);
+                    assert_eq!(payload, b"first".to_vec());
+                },
+                _ => panic!("expected Message"),
+            }
+        }
+        assert_eq!(spool.len(&recipient).unwrap(), 2);
+
+        // The next sequence number acknowledges and deletes it.
+        match on_retrieve_message(&spool, peer_id, &mut state, 1).unwrap() {
+            Command::MessageACK { queue_size_hint, sequence, id, payload } => {
+                assert_eq!(queue_size_hint, 0);
+                assert_eq!(sequence, 1);
+                assert_eq!(id, [3u8; SURB_ID_SIZE]);
+                assert_eq!(payload, b"second".to_vec());
+            },
+            _ => panic!("expected MessageACK"),
+        }
+        assert_eq!(spool.len(&recipient).unwrap(), 1);
+
+        match on_retrieve_message(&spool, peer_id, &mut state, 3) {
+            Err(RetrieveMessageError::OutOfSequence) => {},
+            _ => panic!("expected OutOfSequence"),
+        }
+        assert_eq!(spool.len(&recipient).unwrap(), 1);
+
+        match on_retrieve_message(&spool, peer_id, &mut state, 2).unwrap() {
+            Command::MessageEmpty { sequence } => assert_eq!(sequence, 2),
+            _ => panic!("expected MessageEmpty"),
+        }
+        assert_eq!(spool.len(&recipient).unwrap(), 0);
+    }
+
     #[test]
     fn basic_wire_worker_test() {
         let mut rng = OsRng::new().unwrap();
@@ -259,10 +712,17 @@ mod tests {
         let (crypto_worker_tx, crypto_worker_rx) = unbounded();
         let cfg = WireConfig {
             link_private_key: mix_priv_key,
+            identity: [1u8; 32],
             tcp_fount_rx: tcp_fount_rx,
             crypto_worker_tx: crypto_worker_tx,
             peer_auth_builder: auth_builder,
             is_provider: true,
+            spool: None,
+            consensus: ConsensusStore::new(),
+            clock: Clock::new_katzenpost(),
+            sessions: SessionTracker::new(),
+            metrics: Metrics::new(),
+            tracer: Tracer::default(),
         };
         start_wire_worker(cfg);
 